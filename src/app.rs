@@ -1,16 +1,34 @@
 use super::{
-    metro::Metro,
-    sampler::{Direction, Step, StepBuilder},
-    widgets::{Layout, Page, SequencerWidget, StepEditorWidget},
+    cli,
+    destruction::{self, PRESET_SLOTS},
+    event::{Event, EventKind, EventSender, Param},
+    filter,
+    link::Session,
+    metro::{self, Clock, ClockFollower, Metro, PulseTime, StepTime, TapTempo},
+    midi::{self, ClockIn, MidiOut, NoteIn, NoteMessage, NoteOut},
+    project::Project,
+    sampler::{Direction, Step, StepBuilder, Stretch},
+    sequence::{self, Chain, Division, Pattern},
+    slices::{SliceMap, MAX_SLICES},
+    widgets::{
+        self, FxParam, FxWidget, Layout, NavWidget, Page, PerformWidget, SequencerWidget,
+        SliceWidget, StepEditorWidget, SLICE_COUNTS, SLICE_PAGES,
+    },
 };
 use monome::{KeyDirection, Monome, MonomeDevice, MonomeDeviceType, MonomeEvent};
-use std::{collections::HashSet, sync::mpsc::Sender};
+use std::{collections::HashSet, path::PathBuf, sync::mpsc::Receiver};
 
 use super::common::*;
 
+// The grid is redrawn every 32nd note, the shortest step a pattern can have
+const RENDER_PULSES: u64 = 3;
+
 struct Pages {
     sequencer: Page,
     step_edit: Page,
+    slices: Page,
+    perform: Page,
+    fx: Page,
 }
 
 pub struct App {
@@ -18,14 +36,68 @@ pub struct App {
     current_page: Screen,
     pages: Pages,
     pressed: HashSet<usize>,
-    step_index: usize,
-    num_patterns: usize,
-    sequence: Vec<Option<Step>>,
-    sender: Sender<Step>,
+    // Whether the step editor's pitch row is editing detune rather than semitones
+    fine_pitch: bool,
+    // Whether it's editing the step's nudge or velocity instead
+    edit_timing: bool,
+    edit_velocity: bool,
+    edit_cutoff: bool,
+    // A copy of where the sampler's bank is cut, and which 16 slices the slice rows show
+    slice_maps: Vec<SliceMap>,
+    slice_page: usize,
+    // Every pattern plays at once unless they're chained, `playheads` holds the step each one is
+    // on and `playhead_times` where it lands
+    patterns: Vec<Pattern>,
+    chain: Option<Chain>,
+    playheads: Vec<usize>,
+    playhead_times: Vec<StepTime>,
+    // Held to set the length of the pattern on show
+    length_held: bool,
+    sender: EventSender,
+    bpm: u32,
+    swing: f32,
+    filter: filter::Settings,
+    // A copy of the distortion's preset slots and the one it's on, edited on the FX page
+    presets: [destruction::Params; PRESET_SLOTS],
+    preset: usize,
+    // The tempo the sampler was last told about
+    sampler_bpm: u32,
+    tap_tempo: TapTempo,
+    // Tempos typed in on the terminal
+    tempo_input: Receiver<u32>,
+    // Steps also go out as MIDI notes with these, or only as notes if `midi_only`
+    notes: Option<NoteOut>,
+    midi_only: bool,
+    // Notes played in, from MIDI or the perform page, are triggered straight away on `live`
+    note_in: Option<NoteIn>,
+    live: Option<EventSender>,
+    // And written into the pattern on show while `recording`, nudged to where they were played
+    // unless `quantize`. Without `overdub` the playhead erases each step it passes that hasn't
+    // been played in since, flagged in `fresh`.
+    recording: bool,
+    quantize: bool,
+    overdub: bool,
+    fresh: [bool; GRID_WIDTH],
+    // Everything else the save key needs to write the project
+    samples: Vec<PathBuf>,
+    sample_bpms: Vec<Option<f32>>,
+    sample_rate: u32,
+    clock: Clock,
+    // Where the audio thread is between buffers, for placing notes played in
+    follower: ClockFollower,
+    project_path: PathBuf,
 }
 
 impl App {
-    pub fn new(grid: &MonomeDevice, sender: Sender<Step>) -> Result<Self, String> {
+    pub fn new(
+        grid: &MonomeDevice,
+        sender: EventSender,
+        project: &Project,
+        slice_maps: Vec<SliceMap>,
+        sample_rate: u32,
+        clock: Clock,
+        project_path: PathBuf,
+    ) -> Result<Self, String> {
         assert_eq!(grid.device_type(), MonomeDeviceType::Grid);
         let mut grid = Monome::from_device(grid, "/prefix").map_err(|e| e.to_string())?;
 
@@ -39,88 +111,631 @@ impl App {
 
         let sequencer = Page::new();
         let step_edit = Page::new();
+        let slices = Page::new();
+        let perform = Page::new();
+        let fx = Page::new();
         let mut this = App {
             grid,
             current_page: Screen::Sequencer(DEFAULT_PATTERN),
             pages: Pages {
                 sequencer,
                 step_edit,
+                slices,
+                perform,
+                fx,
             },
             pressed: HashSet::with_capacity(16),
-            step_index: 0,
+            fine_pitch: false,
+            edit_timing: false,
+            edit_velocity: false,
+            edit_cutoff: false,
+            slice_maps,
+            slice_page: 0,
             sender,
-            num_patterns: DEFAULT_NUM_PATTERNS,
-            sequence: vec![None; SEQUENCE_LEN],
+            patterns: project.patterns(),
+            chain: project.chain.then(Chain::default),
+            playheads: vec![0; project.num_patterns.max(1)],
+            playhead_times: vec![StepTime::default(); project.num_patterns.max(1)],
+            length_held: false,
+            bpm: project.bpm,
+            swing: project.swing,
+            filter: project.filter,
+            presets: project.presets(),
+            preset: project.preset.min(PRESET_SLOTS - 1),
+            sampler_bpm: project.bpm,
+            tap_tempo: TapTempo::default(),
+            tempo_input: cli::tempo_input(),
+            notes: None,
+            midi_only: false,
+            note_in: None,
+            live: None,
+            recording: false,
+            quantize: true,
+            overdub: true,
+            fresh: [false; GRID_WIDTH],
+            samples: project
+                .samples
+                .iter()
+                .map(|sample| sample.path.clone())
+                .collect(),
+            sample_bpms: project.samples.iter().map(|sample| sample.bpm).collect(),
+            sample_rate,
+            follower: ClockFollower::new(clock.clone(), sample_rate),
+            clock,
+            project_path,
         };
 
-        for x in 0..16 {
-            SequencerWidget::Pattern(x).render(&mut this.pages.sequencer, false, this.num_patterns);
+        this.write_pattern(DEFAULT_PATTERN);
+        SequencerWidget::PatternSelect(DEFAULT_PATTERN).render(
+            &mut this.pages.sequencer,
+            true,
+            this.patterns.len(),
+        );
+        for widget in [
+            SequencerWidget::Length,
+            SequencerWidget::Record,
+            SequencerWidget::Save,
+            SequencerWidget::TempoDown,
+            SequencerWidget::TempoUp,
+            SequencerWidget::Tap,
+        ] {
+            widget.render(&mut this.pages.sequencer, false, this.patterns.len());
         }
+        SequencerWidget::Swing(widgets::swing_to_key(this.swing)).render(
+            &mut this.pages.sequencer,
+            true,
+            this.patterns.len(),
+        );
+        NavWidget::Slices.render(&mut this.pages.sequencer, false, ());
+        NavWidget::Perform.render(&mut this.pages.sequencer, false, ());
+        NavWidget::Fx.render(&mut this.pages.sequencer, false, ());
+
+        Ok(this)
+    }
+
+    /// Draws column `x` of `pattern`, lit if the step is set or `on`
+    fn write_step(&mut self, pattern: usize, x: usize, on: bool) {
+        let set = self.patterns[pattern].steps[x].is_some();
+        let widget = if x < self.patterns[pattern].length() {
+            SequencerWidget::Pattern(x)
+        } else {
+            SequencerWidget::PastEnd(x)
+        };
+        widget.render(&mut self.pages.sequencer, on || set, self.patterns.len())
+    }
 
-        for x in 0..DEFAULT_NUM_PATTERNS {
-            this.pages.sequencer.framebuffer[x] = OFF;
+    fn write_pattern(&mut self, pattern: usize) {
+        for x in 0..GRID_WIDTH {
+            self.write_step(pattern, x, false)
         }
 
-        this.pages.sequencer.framebuffer[0] = ON;
+        let division = self.patterns[pattern].division;
+        let key = Division::ALL.iter().position(|&d| d == division);
+        SequencerWidget::Division(key.unwrap_or_default()).render(
+            &mut self.pages.sequencer,
+            true,
+            self.patterns.len(),
+        );
+    }
 
-        Ok(this)
+    fn slice_count(&self, sample: usize) -> usize {
+        self.slice_maps.get(sample).map_or(0, SliceMap::len)
     }
 
-    fn write_pattern(&mut self, page: usize) {
-        for x in 0..GRID_WIDTH {
-            SequencerWidget::Pattern(x).render(
-                &mut self.pages.sequencer,
-                self.sequence[x + page * GRID_WIDTH].is_some(),
-                self.num_patterns,
+    fn save(&self) {
+        let project = Project {
+            filter: self.filter,
+            chain: self.chain.is_some(),
+            presets: self.presets.to_vec(),
+            preset: self.preset,
+            ..Project::new(
+                self.bpm,
+                self.swing,
+                &self.patterns,
+                &self.samples,
+                &self.slice_maps,
+                &self.sample_bpms,
+                self.sample_rate,
             )
+        };
+        match project.save(&self.project_path) {
+            Ok(()) => println!("Saved project to {}", self.project_path.display()),
+            Err(e) => println!("Couldn't save {}: {e}", self.project_path.display()),
+        }
+    }
+
+    /// Key on the slice row for `slice`, past the end of the row if it's on another page
+    fn slice_key(&self, slice: usize) -> usize {
+        slice
+            .checked_sub(self.slice_page * GRID_WIDTH)
+            .filter(|&key| key < GRID_WIDTH)
+            .unwrap_or(GRID_WIDTH)
+    }
+
+    /// Draws every parameter row of the step editor for `step_builder`
+    fn render_step_editor(&mut self, step_builder: StepBuilder) {
+        let count = self.slice_count(step_builder.sample());
+        let key = self.slice_key(step_builder.slice());
+        let has_next = (self.slice_page + 1) * GRID_WIDTH < count;
+        let keys_used = count
+            .saturating_sub(self.slice_page * GRID_WIDTH)
+            .min(GRID_WIDTH);
+        let samples = self.slice_maps.len();
+
+        let page = &mut self.pages.step_edit;
+        StepEditorWidget::SliceSelect(key).render(page, true, keys_used);
+        StepEditorWidget::PrevSlices.render(page, self.slice_page > 0, 0);
+        StepEditorWidget::NextSlices.render(page, has_next, 0);
+        StepEditorWidget::SampleSelect(step_builder.sample()).render(page, true, samples);
+
+        let dir = if let Direction::Forward = step_builder.direction() {
+            StepEditorWidget::Forward
+        } else {
+            StepEditorWidget::Backward
+        };
+        dir.render(page, true, 0);
+
+        StepEditorWidget::Pan(widgets::pan_to_key(step_builder.pan())).render(page, true, 0);
+
+        if self.edit_velocity {
+            let key = widgets::velocity_to_key(step_builder.velocity());
+            StepEditorWidget::VelocityFader(key).render(page, true, 0);
+        } else if self.edit_cutoff {
+            let key = widgets::cutoff_to_key(step_builder.cutoff());
+            StepEditorWidget::CutoffFader(key).render(page, true, 0);
+        } else {
+            let key = if self.edit_timing {
+                widgets::nudge_to_key(step_builder.nudge())
+            } else {
+                widgets::pitch_to_key(step_builder.pitch(), self.fine_pitch)
+            };
+            StepEditorWidget::Pitch(key).render(page, true, 0);
+        }
+        StepEditorWidget::Fine.render(page, self.fine_pitch, 0);
+        StepEditorWidget::Timing.render(page, self.edit_timing, 0);
+        StepEditorWidget::Velocity.render(page, self.edit_velocity, 0);
+
+        let stretch = Stretch::ALL
+            .iter()
+            .position(|&s| s == step_builder.stretch());
+        StepEditorWidget::Stretch(stretch.unwrap_or_default()).render(page, true, 0);
+        StepEditorWidget::Filter.render(page, self.edit_cutoff, 0);
+        StepEditorWidget::FilterEnvelope(widgets::envelope_to_key(step_builder.filter_envelope()))
+            .render(page, true, 0);
+        StepEditorWidget::Repeats(widgets::repeats_to_key(step_builder.repeats()))
+            .render(page, true, 0);
+        StepEditorWidget::RepeatPitch(widgets::repeat_pitch_to_key(step_builder.repeat_pitch()))
+            .render(page, true, 0);
+        StepEditorWidget::RepeatDecay(widgets::repeat_decay_to_key(step_builder.repeat_decay()))
+            .render(page, true, 0);
+        StepEditorWidget::FxSlot(step_builder.fx()).render(page, true, 0);
+    }
+
+    /// Draws the slice page with `slice` of `sample` selected
+    fn render_slice_page(&mut self, sample: usize, slice: usize) {
+        let count = self.slice_count(sample);
+        let key = self.slice_key(slice);
+        let keys_used = count
+            .saturating_sub(self.slice_page * GRID_WIDTH)
+            .min(GRID_WIDTH);
+
+        let page = &mut self.pages.slices;
+        SliceWidget::Slice(key).render(page, true, keys_used);
+        SliceWidget::SlicePage(self.slice_page).render(page, true, 0);
+        match SLICE_COUNTS.iter().position(|&c| c == count) {
+            Some(idx) => SliceWidget::Count(idx).render(page, true, 0),
+            None => SliceWidget::Count(0).render(page, false, 0),
+        }
+        SliceWidget::Nudge(0).render(page, false, 0);
+        SliceWidget::SampleSelect(sample).render(page, true, 0);
+        NavWidget::Slices.render(page, true, ());
+    }
+
+    /// Plays a slice straight away so edits can be heard
+    fn audition(&mut self, sample: usize, slice: usize) {
+        let step = StepBuilder::default().with_sample(sample).with_slice(slice);
+        self.play_live(step)
+    }
+
+    /// Draws the perform page with the pads for `sample`
+    fn render_perform_page(&mut self, sample: usize) {
+        let count = self.slice_count(sample);
+        let page = &mut self.pages.perform;
+        PerformWidget::SampleSelect(sample).render(page, true, count);
+        PerformWidget::Record.render(page, self.recording, count);
+        PerformWidget::Quantize.render(page, self.quantize, count);
+        PerformWidget::Overdub.render(page, self.overdub, count);
+        for slice in 0..MAX_SLICES {
+            PerformWidget::Pad(slice).render(page, false, count);
         }
+        NavWidget::Perform.render(page, true, ());
     }
 
-    fn tick(&mut self) {
-        if let Some(step) = self.sequence[self.step_index] {
-            self.sender.send(step).unwrap()
+    fn handle_perform_event(&mut self, event: MonomeEvent, pattern: usize, sample: usize) {
+        let MonomeEvent::GridKey { x, y, direction } = event else {
+            return;
+        };
+        let (x, y) = (x as usize, y as usize);
+        let count = self.slice_count(sample);
+
+        if let KeyDirection::Up = direction {
+            if let Some(widget @ PerformWidget::Pad(_)) = PerformWidget::hit(x, y) {
+                widget.render(&mut self.pages.perform, false, count);
+            }
+            return;
+        }
+
+        if let Some(NavWidget::Perform) = NavWidget::hit(x, y) {
+            self.current_page = Screen::Sequencer(pattern);
+            return;
+        }
+
+        let Some(widget) = PerformWidget::hit(x, y) else {
+            return;
+        };
+        match widget {
+            PerformWidget::SampleSelect(selected) if selected < self.slice_maps.len() => {
+                self.current_page = Screen::Perform {
+                    pattern,
+                    sample: selected,
+                };
+                self.render_perform_page(selected);
+            }
+            PerformWidget::SampleSelect(_) => (),
+            PerformWidget::Record => self.toggle_recording(),
+            PerformWidget::Quantize => {
+                self.quantize = !self.quantize;
+                widget.render(&mut self.pages.perform, self.quantize, count);
+            }
+            PerformWidget::Overdub => {
+                self.overdub = !self.overdub;
+                widget.render(&mut self.pages.perform, self.overdub, count);
+            }
+            PerformWidget::Pad(slice) if slice < count => {
+                let step = StepBuilder::default().with_sample(sample).with_slice(slice);
+                self.play_live(step);
+                if self.recording {
+                    self.record(step);
+                }
+                widget.render(&mut self.pages.perform, true, count);
+            }
+            PerformWidget::Pad(_) => (),
         }
+    }
 
+    /// Draws the FX page for the preset slot the distortion is on
+    fn render_fx_page(&mut self) {
+        let page = &mut self.pages.fx;
+        let params = self.presets[self.preset];
+        FxWidget::Preset(self.preset).render(page, true, ());
+        for param in FxParam::ALL {
+            FxWidget::Fader(param, param.to_key(&params)).render(page, true, ());
+        }
+        NavWidget::Fx.render(page, true, ());
+    }
+
+    fn handle_fx_event(&mut self, event: MonomeEvent, pattern: usize) {
+        let MonomeEvent::GridKey {
+            x,
+            y,
+            direction: KeyDirection::Down,
+        } = event
+        else {
+            return;
+        };
+        let (x, y) = (x as usize, y as usize);
+
+        if let Some(NavWidget::Fx) = NavWidget::hit(x, y) {
+            self.current_page = Screen::Sequencer(pattern);
+            return;
+        }
+
+        // Picking a slot switches the distortion over to it, and the faders then edit it
+        match FxWidget::hit(x, y) {
+            Some(FxWidget::Preset(slot)) => {
+                self.preset = slot;
+                self.send_live(EventKind::Param(Param::Recall(slot)));
+                self.render_fx_page();
+            }
+            Some(widget @ FxWidget::Fader(param, key)) => {
+                let params = param.with_key(key, self.presets[self.preset]);
+                self.presets[self.preset] = params;
+                self.send_live(EventKind::Param(Param::Preset {
+                    slot: self.preset,
+                    params,
+                }));
+                widget.render(&mut self.pages.fx, true, ());
+            }
+            None => (),
+        }
+    }
+
+    fn handle_slice_event(
+        &mut self,
+        event: MonomeEvent,
+        pattern: usize,
+        sample: usize,
+        slice: usize,
+    ) {
+        let MonomeEvent::GridKey {
+            x,
+            y,
+            direction: KeyDirection::Down,
+        } = event
+        else {
+            return;
+        };
+        let (x, y) = (x as usize, y as usize);
+
+        if let Some(NavWidget::Slices) = NavWidget::hit(x, y) {
+            self.current_page = Screen::Sequencer(pattern);
+            return;
+        }
+
+        let count = self.slice_count(sample);
+        let (sample, slice) = match SliceWidget::hit(x, y) {
+            Some(SliceWidget::Slice(key)) => {
+                let selected = self.slice_page * GRID_WIDTH + key;
+                if selected >= count {
+                    return;
+                }
+                self.audition(sample, selected);
+                (sample, selected)
+            }
+            Some(SliceWidget::SlicePage(slice_page)) => {
+                if slice_page * GRID_WIDTH < count {
+                    self.slice_page = slice_page;
+                }
+                (sample, slice)
+            }
+            Some(SliceWidget::Count(idx)) => {
+                let count = SLICE_COUNTS[idx];
+                self.send(EventKind::Param(Param::Slices { sample, count }));
+                if let Some(slices) = self.slice_maps.get_mut(sample) {
+                    slices.reslice(count);
+                }
+
+                let slice = slice.min(count - 1);
+                self.slice_page = slice / GRID_WIDTH;
+                (sample, slice)
+            }
+            Some(SliceWidget::Nudge(frames)) => {
+                self.send(EventKind::Param(Param::Nudge {
+                    sample,
+                    slice,
+                    frames,
+                }));
+                if let Some(slices) = self.slice_maps.get_mut(sample) {
+                    slices.nudge(slice, frames);
+                }
+                self.audition(sample, slice);
+                (sample, slice)
+            }
+            Some(SliceWidget::SampleSelect(selected)) if selected < self.slice_maps.len() => {
+                self.slice_page = 0;
+                (selected, 0)
+            }
+            _ => return,
+        };
+
+        self.current_page = Screen::Slices {
+            pattern,
+            sample,
+            slice,
+        };
+        self.render_slice_page(sample, slice);
+    }
+
+    fn schedule(&mut self, event: Event) {
+        if self.sender.push(event).is_err() {
+            println!("Event queue full, dropped {:?}", event.kind);
+        }
+    }
+
+    /// Sends `kind` to be acted on straight away
+    fn send(&mut self, kind: EventKind) {
+        self.schedule(Event::now(kind))
+    }
+
+    /// Picked up by the metro before the next step
+    fn set_bpm(&mut self, bpm: u32) {
+        self.bpm = bpm.clamp(metro::MIN_BPM, metro::MAX_BPM);
+        println!("Tempo: {} BPM", self.bpm);
+    }
+
+    /// Drops the steps scheduled ahead of a clock that's stopped, and anything still sounding
+    fn stop(&mut self) {
+        self.send(EventKind::Flush);
+        println!("Clock stopped");
+    }
+
+    /// Carries on from `pulse` after the clock starts over or jumps
+    fn seek(&mut self, pulse: u64) {
+        if let Some(chain) = &mut self.chain {
+            chain.seek(&self.patterns, pulse);
+        }
+    }
+
+    /// How many slices each sample in the bank is cut into, for mapping slices to notes
+    fn slice_counts(&self) -> impl Iterator<Item = usize> + '_ {
+        self.slice_maps.iter().map(SliceMap::len)
+    }
+
+    /// The pattern being edited, whichever page is showing
+    fn shown_pattern(&self) -> usize {
         match self.current_page {
-            Screen::Sequencer(page) => {
-                // First handle clearing the current step marker
-                let current_page = self.step_index / GRID_WIDTH;
-
-                if current_page == page {
-                    let step_x = self.step_index % GRID_WIDTH;
-
-                    // Just restore this step's state based on whether it has a note
-                    SequencerWidget::Pattern(step_x).render(
-                        &mut self.pages.sequencer,
-                        self.sequence[self.step_index].is_some(),
-                        self.num_patterns,
-                    )
+            Screen::Sequencer(pattern) => pattern,
+            Screen::StepEdit { page, .. } => page,
+            Screen::Slices { pattern, .. } => pattern,
+            Screen::Perform { pattern, .. } => pattern,
+            Screen::Fx { pattern } => pattern,
+        }
+    }
+
+    /// Arms or disarms recording, from either of the keys that do it
+    fn toggle_recording(&mut self) {
+        self.recording = !self.recording;
+        self.fresh = [false; GRID_WIDTH];
+        PerformWidget::Record.render(&mut self.pages.perform, self.recording, 0);
+        SequencerWidget::Record.render(
+            &mut self.pages.sequencer,
+            self.recording,
+            self.patterns.len(),
+        );
+    }
+
+    /// Plays a note straight away, and records it if recording
+    fn play_note(&mut self, note: NoteMessage) {
+        let Some((sample, slice)) = midi::note_to_slice(note.note, self.slice_counts()) else {
+            return;
+        };
+        let step = StepBuilder::default()
+            .with_sample(sample)
+            .with_slice(slice)
+            .with_velocity(note.level());
+        self.play_live(step);
+        if self.recording {
+            self.record(step);
+        }
+    }
+
+    /// Triggers `step` ahead of anything already scheduled
+    fn play_live(&mut self, step: StepBuilder) {
+        self.send_live(EventKind::Trigger(step))
+    }
+
+    /// Sends `kind` ahead of anything already scheduled
+    fn send_live(&mut self, kind: EventKind) {
+        let event = Event::now(kind);
+        match &mut self.live {
+            Some(live) => {
+                if live.push(event).is_err() {
+                    println!("Live queue full, dropping {kind:?}");
                 }
+            }
+            None => self.schedule(event),
+        }
+    }
+
+    /// Writes `step` onto the step of the pattern on show that's being heard now
+    fn record(&mut self, step: StepBuilder) {
+        let pattern = self.shown_pattern();
+        let time = self.playhead_times[pattern];
+        if time.len <= 0. {
+            return;
+        }
 
-                // Advance to next step
-                self.step_index = (self.step_index + 1) % self.sequence.len();
-
-                // If new step is on current page, render the cursor
-                let new_page = self.step_index / GRID_WIDTH;
-                if new_page == page {
-                    SequencerWidget::Pattern(self.step_index % GRID_WIDTH).render(
-                        &mut self.pages.sequencer,
-                        true,
-                        self.num_patterns,
-                    )
+        // Steps are scheduled ahead, so the playhead is usually a step or two past what's
+        // being heard
+        let steps = (self.follower.now() - time.start) / time.len;
+        let offset = steps.round();
+        let length = self.patterns[pattern].length() as isize;
+        let index =
+            (self.playheads[pattern] as isize + offset as isize).rem_euclid(length) as usize;
+        let step = if self.quantize {
+            step
+        } else {
+            let swing = if index % 2 == 1 { self.swing } else { 0. };
+            let nudge = (steps - offset) as f32 - swing;
+            step.with_nudge(nudge.clamp(-metro::MAX_NUDGE, metro::MAX_NUDGE))
+        };
+
+        self.patterns[pattern].steps[index] = Some(Step::On(step));
+        self.fresh[index] = true;
+        if let Screen::Sequencer(shown) = self.current_page {
+            if shown == pattern {
+                self.write_step(pattern, index, index == self.playheads[pattern]);
+            }
+        }
+    }
+
+    /// Schedules every step starting on the pulse at `time` and moves the playheads on
+    fn tick(&mut self, time: PulseTime) {
+        if self.bpm != self.sampler_bpm {
+            self.sampler_bpm = self.bpm;
+            let frame = time.start.round() as u64;
+            self.schedule(Event::at(frame, EventKind::Tempo(self.bpm)));
+        }
+
+        let replacing = self.recording && !self.overdub;
+        let shown_pattern = self.shown_pattern();
+        let chained = self.chain.map(|chain| chain.pattern());
+        let steps = sequence::steps_at(&self.patterns, self.chain.as_mut(), time.pulse);
+
+        // Take the cursor off a chained pattern once it's played through
+        if let (Some(last), Some(chain)) = (chained, self.chain) {
+            if let Screen::Sequencer(shown) = self.current_page {
+                if shown == last && chain.pattern() != last {
+                    self.write_step(last, self.playheads[last], false);
                 }
+            }
+        }
 
-                self.pages.sequencer.render(&mut self.grid);
+        for (idx, step) in steps {
+            if replacing && idx == shown_pattern && !std::mem::take(&mut self.fresh[step]) {
+                self.patterns[idx].steps[step] = None;
+            }
+
+            let pattern = &self.patterns[idx];
+            let step_time = pattern.step_time(time);
+            if let Some(event) = pattern.event(step, time, self.swing) {
+                if let (Some(notes), EventKind::Trigger(step)) = (&self.notes, &event.kind) {
+                    let note =
+                        midi::slice_to_note(step.sample(), step.slice(), self.slice_counts());
+                    if let Some(note) = note {
+                        notes.play(event.frame, step_time.len, note, step);
+                    }
+                }
+                // Follow the sampler onto any preset the step recalls
+                if let EventKind::Trigger(step) = event.kind {
+                    if let Some(slot) = step.fx().filter(|&slot| slot < PRESET_SLOTS) {
+                        if slot != self.preset {
+                            self.preset = slot;
+                            if let Screen::Fx { .. } = self.current_page {
+                                self.render_fx_page();
+                            }
+                        }
+                    }
+                }
+                if !self.midi_only {
+                    self.schedule(event);
+                }
             }
 
-            Screen::StepEdit { .. } => {
-                self.step_index = (self.step_index + 1) % self.sequence.len();
-                self.pages.step_edit.render(&mut self.grid)
+            self.playhead_times[idx] = step_time;
+
+            // Move the cursor if this is the pattern on show
+            let last = std::mem::replace(&mut self.playheads[idx], step);
+            if let Screen::Sequencer(shown) = self.current_page {
+                if shown == idx {
+                    self.write_step(idx, last, false);
+                    self.write_step(idx, step, true);
+                }
             }
         }
+
+        if !time.pulse.is_multiple_of(RENDER_PULSES) {
+            return;
+        }
+        match self.current_page {
+            Screen::Sequencer(_) => self.pages.sequencer.render(&mut self.grid),
+            Screen::StepEdit { .. } => self.pages.step_edit.render(&mut self.grid),
+            Screen::Slices { .. } => self.pages.slices.render(&mut self.grid),
+            Screen::Perform { .. } => self.pages.perform.render(&mut self.grid),
+            Screen::Fx { .. } => self.pages.fx.render(&mut self.grid),
+        }
     }
 
     fn handle_event(&mut self) -> bool {
+        if let Ok(bpm) = self.tempo_input.try_recv() {
+            self.set_bpm(bpm);
+            return true;
+        }
+        if let Some(note) = self.note_in.as_ref().and_then(NoteIn::try_recv) {
+            self.play_note(note);
+            return true;
+        }
+
         match self.grid.poll() {
             Some(event) => {
                 match self.current_page {
@@ -130,19 +745,96 @@ impl App {
                             y,
                             direction: KeyDirection::Down,
                         } => {
-                            if let Some(widget) = SequencerWidget::hit(x as usize, y as usize) {
+                            if let Some(NavWidget::Slices) = NavWidget::hit(x as usize, y as usize)
+                            {
+                                self.slice_page = 0;
+                                self.current_page = Screen::Slices {
+                                    pattern: page,
+                                    sample: 0,
+                                    slice: 0,
+                                };
+                                self.render_slice_page(0, 0);
+                            } else if let Some(NavWidget::Perform) =
+                                NavWidget::hit(x as usize, y as usize)
+                            {
+                                self.current_page = Screen::Perform {
+                                    pattern: page,
+                                    sample: 0,
+                                };
+                                self.render_perform_page(0);
+                            } else if let Some(NavWidget::Fx) =
+                                NavWidget::hit(x as usize, y as usize)
+                            {
+                                self.current_page = Screen::Fx { pattern: page };
+                                self.render_fx_page();
+                            } else if let Some(widget) =
+                                SequencerWidget::hit(x as usize, y as usize)
+                            {
                                 match widget {
+                                    SequencerWidget::Save => {
+                                        self.save();
+                                        widget.render(
+                                            &mut self.pages.sequencer,
+                                            true,
+                                            self.patterns.len(),
+                                        );
+                                    }
+                                    SequencerWidget::TempoDown
+                                    | SequencerWidget::TempoUp
+                                    | SequencerWidget::Tap => {
+                                        let bpm = match widget {
+                                            SequencerWidget::TempoDown => {
+                                                Some(self.bpm.saturating_sub(1))
+                                            }
+                                            SequencerWidget::TempoUp => Some(self.bpm + 1),
+                                            _ => self.tap_tempo.tap(),
+                                        };
+                                        if let Some(bpm) = bpm {
+                                            self.set_bpm(bpm);
+                                        }
+                                        widget.render(
+                                            &mut self.pages.sequencer,
+                                            true,
+                                            self.patterns.len(),
+                                        );
+                                    }
+                                    SequencerWidget::Swing(key) => {
+                                        self.swing = widgets::key_to_swing(key);
+                                        widget.render(
+                                            &mut self.pages.sequencer,
+                                            true,
+                                            self.patterns.len(),
+                                        );
+                                    }
+                                    SequencerWidget::Division(key) => {
+                                        self.patterns[page].division = Division::ALL[key];
+                                        self.write_pattern(page);
+                                    }
+                                    SequencerWidget::Record => self.toggle_recording(),
+                                    SequencerWidget::Length => {
+                                        self.length_held = true;
+                                        widget.render(
+                                            &mut self.pages.sequencer,
+                                            true,
+                                            self.patterns.len(),
+                                        );
+                                    }
+                                    SequencerWidget::PastEnd(_) => unreachable!(),
+                                    SequencerWidget::Pattern(step) if self.length_held => {
+                                        self.patterns[page].set_length(step + 1);
+                                        self.write_pattern(page);
+                                    }
                                     SequencerWidget::PatternSelect(selected_page) => {
                                         self.pressed.insert(selected_page);
                                         widget.render(
                                             &mut self.pages.sequencer,
                                             true,
-                                            self.num_patterns,
+                                            self.patterns.len(),
                                         );
                                     }
                                     SequencerWidget::Pattern(step) => {
                                         self.write_pattern(page);
-                                        let step_builder = self.sequence[step + page * GRID_WIDTH]
+                                        let step_builder = self.patterns[page].steps[step]
                                             .and_then(|s| match s {
                                                 Step::On(current_step) => Some(current_step),
                                                 _ => None,
@@ -152,28 +844,12 @@ impl App {
                                         self.current_page = Screen::StepEdit {
                                             page,
                                             step,
+                                            row: y as usize,
                                             step_builder,
                                         };
+                                        self.slice_page = step_builder.slice() / GRID_WIDTH;
 
-                                        StepEditorWidget::SliceSelect(step_builder.slice()).render(
-                                            &mut self.pages.step_edit,
-                                            true,
-                                            (),
-                                        );
-
-                                        let dir =
-                                            if let Direction::Forward = step_builder.direction() {
-                                                StepEditorWidget::Forward
-                                            } else {
-                                                StepEditorWidget::Backward
-                                            };
-                                        dir.render(&mut self.pages.step_edit, true, ());
-
-                                        StepEditorWidget::CurrentStep(x as usize).render(
-                                            &mut self.pages.step_edit,
-                                            true,
-                                            (),
-                                        );
+                                        self.render_step_editor(step_builder);
                                     }
                                 }
                             }
@@ -184,30 +860,51 @@ impl App {
                             y,
                             direction: KeyDirection::Up,
                         } => {
-                            if let Some(widget @ SequencerWidget::PatternSelect(pattern)) =
+                            if let Some(widget @ SequencerWidget::Length) =
+                                SequencerWidget::hit(x as usize, y as usize)
+                            {
+                                self.length_held = false;
+                                widget.render(
+                                    &mut self.pages.sequencer,
+                                    false,
+                                    self.patterns.len(),
+                                );
+                            } else if let Some(
+                                widget @ (SequencerWidget::Save
+                                | SequencerWidget::TempoDown
+                                | SequencerWidget::TempoUp
+                                | SequencerWidget::Tap),
+                            ) = SequencerWidget::hit(x as usize, y as usize)
+                            {
+                                widget.render(
+                                    &mut self.pages.sequencer,
+                                    false,
+                                    self.patterns.len(),
+                                );
+                            } else if let Some(widget @ SequencerWidget::PatternSelect(pattern)) =
                                 SequencerWidget::hit(x as usize, y as usize)
                             {
                                 self.pressed.remove(&pattern);
                                 if self.pressed.is_empty() {
-                                    if pattern < self.num_patterns {
+                                    if pattern < self.patterns.len() {
                                         self.write_pattern(pattern);
                                         widget.render(
                                             &mut self.pages.sequencer,
                                             true,
-                                            self.num_patterns,
+                                            self.patterns.len(),
                                         );
                                         self.current_page = Screen::Sequencer(pattern)
                                     }
                                 } else if self.pressed.contains(&0) {
                                     println!("Hit pattern select: {:?}", pattern);
 
-                                    let added_len =
-                                        (pattern + 1) * GRID_WIDTH - self.sequence.len();
-                                    println!("Length being added: {:?}", added_len);
+                                    let num_patterns = (pattern + 1).max(self.patterns.len());
+                                    println!("Patterns playing: {:?}", num_patterns);
 
-                                    self.num_patterns = pattern + 1;
-                                    self.sequence
-                                        .extend(std::iter::repeat(None).take(added_len));
+                                    self.patterns.resize_with(num_patterns, Pattern::default);
+                                    self.playheads.resize(num_patterns, 0);
+                                    self.playhead_times
+                                        .resize(num_patterns, StepTime::default());
                                     self.write_pattern(pattern)
                                 }
                             }
@@ -218,6 +915,7 @@ impl App {
                     Screen::StepEdit {
                         page,
                         step,
+                        row,
                         step_builder,
                     } => match event {
                         MonomeEvent::GridKey {
@@ -227,53 +925,199 @@ impl App {
                         } => {
                             if let Some(widget) = StepEditorWidget::hit(x as usize, y as usize) {
                                 match widget {
-                                    StepEditorWidget::SliceSelect(_) => {
+                                    StepEditorWidget::SliceSelect(key) => {
+                                        let slice = self.slice_page * GRID_WIDTH + key;
+                                        // Keys past the sample's last slice have nothing behind them
+                                        if slice < self.slice_count(step_builder.sample()) {
+                                            self.current_page
+                                                .set_step(step_builder.with_slice(slice));
+                                            self.render_step_editor(step_builder.with_slice(slice));
+                                        }
+                                    }
+                                    StepEditorWidget::PrevSlices => {
+                                        self.slice_page = self.slice_page.saturating_sub(1);
+                                        self.render_step_editor(step_builder);
+                                    }
+                                    StepEditorWidget::NextSlices => {
+                                        let count = self.slice_count(step_builder.sample());
+                                        let next = self.slice_page + 1;
+                                        if next < SLICE_PAGES && next * GRID_WIDTH < count {
+                                            self.slice_page = next;
+                                        }
+                                        self.render_step_editor(step_builder);
+                                    }
+                                    StepEditorWidget::SampleSelect(sample)
+                                        if sample < self.slice_maps.len() =>
+                                    {
                                         self.current_page
-                                            .set_step(step_builder.with_slice(x as usize));
-                                        widget.render(&mut self.pages.step_edit, true, ());
+                                            .set_step(step_builder.with_sample(sample));
+                                        self.render_step_editor(step_builder.with_sample(sample));
                                     }
-                                    StepEditorWidget::CurrentStep(_) => unreachable!(),
+                                    StepEditorWidget::SampleSelect(_) => (),
+                                    StepEditorWidget::VelocityFader(_)
+                                    | StepEditorWidget::CutoffFader(_) => unreachable!(),
                                     StepEditorWidget::Backward => {
                                         self.current_page.set_step(
                                             step_builder.with_direction(Direction::Backward),
                                         );
-                                        widget.render(&mut self.pages.step_edit, true, ());
+                                        widget.render(&mut self.pages.step_edit, true, 0);
                                     }
                                     StepEditorWidget::Forward => {
                                         self.current_page.set_step(
                                             step_builder.with_direction(Direction::Forward),
                                         );
-                                        widget.render(&mut self.pages.step_edit, true, ());
+                                        widget.render(&mut self.pages.step_edit, true, 0);
+                                    }
+                                    StepEditorWidget::Pitch(key) if self.edit_velocity => {
+                                        let velocity = widgets::key_to_velocity(key);
+                                        self.current_page
+                                            .set_step(step_builder.with_velocity(velocity));
+                                        StepEditorWidget::VelocityFader(key).render(
+                                            &mut self.pages.step_edit,
+                                            true,
+                                            0,
+                                        );
+                                    }
+                                    StepEditorWidget::Pitch(key) if self.edit_cutoff => {
+                                        let cutoff = widgets::key_to_cutoff(key);
+                                        self.current_page
+                                            .set_step(step_builder.with_cutoff(cutoff));
+                                        StepEditorWidget::CutoffFader(key).render(
+                                            &mut self.pages.step_edit,
+                                            true,
+                                            0,
+                                        );
+                                    }
+                                    StepEditorWidget::Pitch(key) if self.edit_timing => {
+                                        let nudge = widgets::key_to_nudge(key);
+                                        self.current_page.set_step(step_builder.with_nudge(nudge));
+                                        widget.render(&mut self.pages.step_edit, true, 0);
+                                    }
+                                    StepEditorWidget::Pitch(key) => {
+                                        let pitch = widgets::key_to_pitch(
+                                            key,
+                                            step_builder.pitch(),
+                                            self.fine_pitch,
+                                        );
+                                        self.current_page.set_step(step_builder.with_pitch(pitch));
+                                        widget.render(&mut self.pages.step_edit, true, 0);
+                                    }
+                                    StepEditorWidget::Fine => {
+                                        self.fine_pitch = true;
+                                        self.render_step_editor(step_builder);
+                                    }
+                                    StepEditorWidget::Timing => {
+                                        self.edit_timing = true;
+                                        self.render_step_editor(step_builder);
+                                    }
+                                    StepEditorWidget::Velocity => {
+                                        self.edit_velocity = true;
+                                        self.render_step_editor(step_builder);
+                                    }
+                                    StepEditorWidget::Pan(key) => {
+                                        self.current_page.set_step(
+                                            step_builder.with_pan(widgets::key_to_pan(key)),
+                                        );
+                                        widget.render(&mut self.pages.step_edit, true, 0);
+                                    }
+                                    StepEditorWidget::Stretch(key) => {
+                                        self.current_page
+                                            .set_step(step_builder.with_stretch(Stretch::ALL[key]));
+                                        widget.render(&mut self.pages.step_edit, true, 0);
+                                    }
+                                    StepEditorWidget::Filter => {
+                                        self.edit_cutoff = true;
+                                        self.render_step_editor(step_builder);
+                                    }
+                                    StepEditorWidget::FilterEnvelope(key) => {
+                                        let octaves = widgets::key_to_envelope(key);
+                                        self.current_page
+                                            .set_step(step_builder.with_filter_envelope(octaves));
+                                        widget.render(&mut self.pages.step_edit, true, 0);
+                                    }
+                                    StepEditorWidget::Repeats(key) => {
+                                        let repeats = widgets::REPEAT_COUNTS[key];
+                                        self.current_page
+                                            .set_step(step_builder.with_repeats(repeats));
+                                        widget.render(&mut self.pages.step_edit, true, 0);
+                                    }
+                                    StepEditorWidget::RepeatPitch(key) => {
+                                        let semitones = widgets::key_to_repeat_pitch(key);
+                                        self.current_page
+                                            .set_step(step_builder.with_repeat_pitch(semitones));
+                                        widget.render(&mut self.pages.step_edit, true, 0);
+                                    }
+                                    StepEditorWidget::RepeatDecay(key) => {
+                                        let decay = widgets::key_to_repeat_decay(key);
+                                        self.current_page
+                                            .set_step(step_builder.with_repeat_decay(decay));
+                                        widget.render(&mut self.pages.step_edit, true, 0);
+                                    }
+                                    // Pressing the slot the step already recalls clears it
+                                    StepEditorWidget::FxSlot(slot) => {
+                                        let slot = slot.filter(|_| slot != step_builder.fx());
+                                        self.current_page.set_step(step_builder.with_fx(slot));
+                                        StepEditorWidget::FxSlot(slot).render(
+                                            &mut self.pages.step_edit,
+                                            true,
+                                            0,
+                                        );
                                     }
                                 }
                             }
                         }
 
                         MonomeEvent::GridKey {
+                            x,
                             y,
                             direction: KeyDirection::Up,
-                            ..
                         } => {
-                            if let Some(SequencerWidget::Pattern(_)) =
-                                SequencerWidget::hit(0, y as usize)
-                            {
+                            if (x as usize, y as usize) == (step, row) {
+                                // Only letting go of the key that opened the editor saves the
+                                // step, the parameter keys sharing its rows and column don't.
+                                // It's checked first as it can sit on the filter key.
                                 println!("Setting step {} to {:?}", step * page, step_builder);
-                                self.sequence[step + page * GRID_WIDTH] =
-                                    Some(Step::On(step_builder));
-
-                                StepEditorWidget::CurrentStep(step).render(
-                                    &mut self.pages.step_edit,
-                                    false,
-                                    (),
-                                );
+                                self.patterns[page].steps[step] = Some(Step::On(step_builder));
 
                                 self.current_page = Screen::Sequencer(page);
                                 self.write_pattern(page);
+                            } else if let Some(StepEditorWidget::Fine) =
+                                StepEditorWidget::hit(x as usize, y as usize)
+                            {
+                                self.fine_pitch = false;
+                                self.render_step_editor(step_builder);
+                            } else if let Some(StepEditorWidget::Timing) =
+                                StepEditorWidget::hit(x as usize, y as usize)
+                            {
+                                self.edit_timing = false;
+                                self.render_step_editor(step_builder);
+                            } else if let Some(StepEditorWidget::Velocity) =
+                                StepEditorWidget::hit(x as usize, y as usize)
+                            {
+                                self.edit_velocity = false;
+                                self.render_step_editor(step_builder);
+                            } else if let Some(StepEditorWidget::Filter) =
+                                StepEditorWidget::hit(x as usize, y as usize)
+                            {
+                                self.edit_cutoff = false;
+                                self.render_step_editor(step_builder);
                             }
                         }
 
                         _ => (),
                     },
+
+                    Screen::Slices {
+                        pattern,
+                        sample,
+                        slice,
+                    } => self.handle_slice_event(event, pattern, sample, slice),
+
+                    Screen::Perform { pattern, sample } => {
+                        self.handle_perform_event(event, pattern, sample)
+                    }
+
+                    Screen::Fx { pattern } => self.handle_fx_event(event, pattern),
                 }
                 true
             }
@@ -281,34 +1125,91 @@ impl App {
         }
     }
 
-    pub fn run(self) {
+    /// Sends steps out as MIDI notes too, or instead of to the sampler if `midi_only`
+    pub fn with_notes(self, notes: NoteOut, midi_only: bool) -> Self {
+        Self {
+            notes: Some(notes),
+            midi_only,
+            ..self
+        }
+    }
+
+    /// Sends slices played in straight to the sampler, rather than queueing them behind steps
+    pub fn with_live_events(self, live: EventSender) -> Self {
+        Self {
+            live: Some(live),
+            ..self
+        }
+    }
+
+    /// Plays and records slices from notes sent in
+    pub fn with_note_in(self, note_in: NoteIn) -> Self {
+        Self {
+            note_in: Some(note_in),
+            ..self
+        }
+    }
+
+    pub fn run(
+        mut self,
+        clock_in: Option<ClockIn>,
+        clock_out: Option<MidiOut>,
+        session: Option<Session>,
+    ) {
         println!("Starting metro :3");
-        let metro = Metro::new(DEFAULT_BPM, self);
-        metro.forever(App::tick, App::handle_event)
+        self.send(EventKind::Tempo(self.bpm));
+        let mut metro = Metro::new(self.bpm, self.sample_rate, self.clock.clone(), self);
+        if let Some(clock_in) = clock_in {
+            println!("Waiting for MIDI clock");
+            metro = metro.with_clock_in(clock_in);
+        }
+        if let Some(clock_out) = clock_out {
+            metro = metro.with_clock_out(clock_out);
+        }
+        if let Some(session) = session {
+            println!("Joined session, starting on the next bar");
+            metro = metro.with_session(session);
+        }
+        metro.forever(
+            App::tick,
+            App::handle_event,
+            |app| app.bpm,
+            App::set_bpm,
+            App::stop,
+            App::seek,
+        )
     }
 }
 
 #[derive(Debug, Clone, Copy)]
 enum Screen {
     Sequencer(usize),
+    // `row` is where the step's key was pressed, letting go of it closes the editor
     StepEdit {
         page: usize,
         step: usize,
+        row: usize,
         step_builder: StepBuilder,
     },
+    Slices {
+        pattern: usize,
+        sample: usize,
+        slice: usize,
+    },
+    Perform {
+        pattern: usize,
+        sample: usize,
+    },
+    Fx {
+        pattern: usize,
+    },
 }
 
 impl Screen {
     fn set_step(&mut self, updated_step: StepBuilder) {
         match self {
-            Self::Sequencer(_) => (),
-            Self::StepEdit { page, step, .. } => {
-                *self = Self::StepEdit {
-                    page: *page,
-                    step: *step,
-                    step_builder: updated_step,
-                }
-            }
+            Self::Sequencer(_) | Self::Slices { .. } | Self::Perform { .. } | Self::Fx { .. } => {}
+            Self::StepEdit { step_builder, .. } => *step_builder = updated_step,
         }
     }
 }
@@ -0,0 +1,140 @@
+use super::{
+    filter,
+    metro::{MAX_BPM, MIN_BPM},
+    midi::ClockSync,
+    onset::DEFAULT_SENSITIVITY,
+    resample::Quality,
+    sampler::{DEFAULT_SLICES, DEFAULT_VOICES, MAX_SAMPLES},
+};
+use clap::{Parser, Subcommand};
+use std::{
+    io::BufRead,
+    path::PathBuf,
+    sync::mpsc::{self, Receiver},
+};
+
+/// A sequencer + sampler for the monome grid
+#[derive(Debug, Parser)]
+#[command(subcommand_precedence_over_arg = true)]
+pub struct Args {
+    /// Samples to load into the bank, selected per step on the step editor. Defaults to the
+    /// project's samples, or amen.wav without one
+    #[arg(num_args = 0..=MAX_SAMPLES)]
+    pub samples: Vec<PathBuf>,
+
+    /// Sequencer tempo, overrides the project's. Type a new one in while running to change it
+    #[arg(long, value_parser = clap::value_parser!(u32).range(MIN_BPM as i64..=MAX_BPM as i64))]
+    pub bpm: Option<u32>,
+
+    /// Fraction of a step the off-beats are pushed late, from 0 to 0.5. Overrides the project's
+    #[arg(long)]
+    pub swing: Option<f32>,
+
+    /// Project file to open if it exists, and to save to from the grid
+    #[arg(long)]
+    pub project: Option<PathBuf>,
+
+    /// Output device name, the system default if omitted
+    #[arg(long)]
+    pub device: Option<String>,
+
+    /// Number of equal slices the sample is cut into
+    #[arg(long, default_value_t = DEFAULT_SLICES)]
+    pub slices: usize,
+
+    /// Slice at detected transients instead of evenly
+    #[arg(long)]
+    pub onsets: bool,
+
+    /// How readily `--onsets` finds a hit, from 0 (only the hardest) to 1 (every ghost note)
+    #[arg(long, default_value_t = DEFAULT_SENSITIVITY)]
+    pub sensitivity: f32,
+
+    /// Number of voices that can play at once
+    #[arg(long, default_value_t = DEFAULT_VOICES)]
+    pub voices: usize,
+
+    /// Take every slice out of the shared choke group so tails can overlap
+    #[arg(long)]
+    pub poly: bool,
+
+    /// Filter every voice plays through: off, lowpass, highpass, bandpass or notch. Overrides
+    /// the project's
+    #[arg(long)]
+    pub filter: Option<filter::Mode>,
+
+    /// The filter's cutoff in Hz, steps can set their own on the step editor
+    #[arg(long)]
+    pub cutoff: Option<f32>,
+
+    /// The filter's resonance, from 0 to 1
+    #[arg(long)]
+    pub resonance: Option<f32>,
+
+    /// Resampler used to convert the sample to the output rate: linear or sinc
+    #[arg(long, default_value = "sinc")]
+    pub resample: Quality,
+
+    /// Sync to MIDI clock: in follows the FerroSeq clock in port, out sends clock from the
+    /// FerroSeq clock out port
+    #[arg(long)]
+    pub midi_clock: Option<ClockSync>,
+
+    /// Send each step out as a MIDI note on this channel, slice 0 playing note 36 and up from
+    /// there
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=16))]
+    pub midi_channel: Option<u8>,
+
+    /// Only send steps out as MIDI notes, leaving the sampler silent
+    #[arg(long, requires = "midi_channel")]
+    pub midi_only: bool,
+
+    /// Play slices from notes sent to the FerroSeq notes in port, slice 0 on note 36
+    #[arg(long)]
+    pub midi_in: bool,
+
+    /// Share tempo and beat phase with other instances on this machine, starting on the
+    /// session's next bar
+    #[arg(long)]
+    pub link: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Bounce the sequence to a WAV file without a sound card or grid attached
+    Render {
+        /// WAV file to write
+        #[arg(default_value = "render.wav")]
+        out: PathBuf,
+
+        #[arg(long, default_value_t = 4)]
+        bars: usize,
+
+        #[arg(long, default_value_t = 48_000)]
+        sample_rate: u32,
+    },
+}
+
+/// Reads tempos typed into the terminal while the grid is running, one BPM per line
+pub fn tempo_input() -> Receiver<u32> {
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            match line.trim().parse::<u32>() {
+                Ok(bpm) => {
+                    if sender.send(bpm).is_err() {
+                        break;
+                    }
+                }
+                Err(_) => println!("Type a tempo in BPM and press enter"),
+            }
+        }
+    });
+    receiver
+}
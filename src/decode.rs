@@ -1,3 +1,5 @@
+use super::{common::Frame, tempo};
+use std::fmt;
 use std::io::ErrorKind;
 use std::path::Path;
 use symphonia::core::audio::SampleBuffer;
@@ -5,12 +7,66 @@ use symphonia::core::codecs::{DecoderOptions, CODEC_TYPE_NULL};
 use symphonia::core::errors::Error;
 use symphonia::core::formats::FormatOptions;
 use symphonia::core::io::MediaSourceStream;
-use symphonia::core::meta::MetadataOptions;
+use symphonia::core::meta::{MetadataOptions, MetadataRevision, StandardTagKey};
 use symphonia::core::probe::Hint;
 
-/// Returns a tuple of samples and number of frames collected
-pub fn decode(path: &Path) -> (Vec<f32>, u64) {
-    let src = std::fs::File::open(path).expect("Cannot find amen break");
+// Assumed when a container doesn't report its rate
+const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+
+#[derive(Debug)]
+pub enum DecodeError {
+    Open(std::io::Error),
+    UnsupportedFormat(Error),
+    NoAudioTrack,
+    UnsupportedCodec(Error),
+    Decode(Error),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Open(e) => write!(f, "Cannot open file: {e}"),
+            Self::UnsupportedFormat(e) => write!(f, "Unsupported format: {e}"),
+            Self::NoAudioTrack => write!(f, "No supported audio tracks"),
+            Self::UnsupportedCodec(e) => write!(f, "Unsupported codec: {e}"),
+            Self::Decode(e) => write!(f, "Decode error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+pub struct Decoded {
+    pub frames: Vec<Frame>,
+    pub sample_rate: u32,
+    // Tempo from the file's tags, if it was tagged with one
+    pub bpm: Option<f32>,
+}
+
+fn tagged_bpm(revision: Option<&MetadataRevision>) -> Option<f32> {
+    revision?
+        .tags()
+        .iter()
+        .filter(|tag| tag.std_key == Some(StandardTagKey::Bpm))
+        .find_map(|tag| tag.value.to_string().trim().parse().ok())
+        .filter(|&bpm| tempo::is_valid(bpm))
+}
+
+// Mono is spread across both channels, anything wider keeps its front left/right pair
+fn to_stereo(interleaved: &[f32], channels: usize) -> impl Iterator<Item = Frame> + '_ {
+    interleaved
+        .chunks(channels.max(1))
+        .map(|frame| match *frame {
+            [mono] => [mono, mono],
+            [left, right, ..] => [left, right],
+            [] => [0., 0.],
+        })
+}
+
+/// Decodes the first audio track in `path` into stereo frames at the file's own sample rate, along
+/// with any tempo it's tagged with
+pub fn decode(path: &Path) -> Result<Decoded, DecodeError> {
+    let src = std::fs::File::open(path).map_err(DecodeError::Open)?;
 
     let mstream = MediaSourceStream::new(Box::new(src), Default::default());
     let mut hint = Hint::new();
@@ -21,19 +77,25 @@ pub fn decode(path: &Path) -> (Vec<f32>, u64) {
     let meta_opts: MetadataOptions = Default::default();
     let fmt_opts: FormatOptions = Default::default();
 
-    let probed = symphonia::default::get_probe()
+    let mut probed = symphonia::default::get_probe()
         .format(&hint, mstream, &fmt_opts, &meta_opts)
-        .expect("Unsupported format");
+        .map_err(DecodeError::UnsupportedFormat)?;
 
+    // Tags ahead of the container, like ID3, then the container's own
+    let bpm = tagged_bpm(probed.metadata.get().as_ref().and_then(|m| m.current()));
     let mut format = probed.format;
+    let bpm = bpm.or_else(|| tagged_bpm(format.metadata().current()));
     let track = format
         .tracks()
         .iter()
         .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
-        .expect("No supported audio tracks");
+        .ok_or(DecodeError::NoAudioTrack)?;
 
     let expected_frames = track.codec_params.n_frames;
-    let num_channels = track.codec_params.channels.map(|c| c.count()).unwrap_or(1);
+    let sample_rate = track
+        .codec_params
+        .sample_rate
+        .unwrap_or(DEFAULT_SAMPLE_RATE);
     // Print track details
     println!("Track info:");
     println!("  Codec: {:?}", track.codec_params.codec);
@@ -47,40 +109,43 @@ pub fn decode(path: &Path) -> (Vec<f32>, u64) {
 
     let mut decoder = symphonia::default::get_codecs()
         .make(&track.codec_params, &dec_opts)
-        .expect("Unsupported codec");
+        .map_err(DecodeError::UnsupportedCodec)?;
 
-    let mut samples_interleaved = Vec::<f32>::new();
+    let mut frames = Vec::<Frame>::new();
     let mut frames_collected = 0;
     loop {
         match format.next_packet().and_then(|p| decoder.decode(&p)) {
             Ok(decoded) => {
-                let spec = decoded.spec();
-                let frames = decoded.frames() as u64;
-                frames_collected += frames;
+                let spec = *decoded.spec();
+                frames_collected += decoded.frames() as u64;
 
                 let duration = decoded.capacity() as u64;
 
-                let mut sample_buf = SampleBuffer::new(duration, *spec);
+                let mut sample_buf = SampleBuffer::new(duration, spec);
                 sample_buf.copy_interleaved_ref(decoded);
-                samples_interleaved.extend(sample_buf.samples());
+                // Chunked by what was decoded rather than what the track claimed, which can be
+                // missing or wrong
+                frames.extend(to_stereo(sample_buf.samples(), spec.channels.count()));
             }
             Err(Error::IoError(e)) if e.kind() == ErrorKind::UnexpectedEof => {
                 println!("Hit EOF");
                 break;
             }
-            Err(e) => panic!("Decode error: {e}"),
+            Err(e) => return Err(DecodeError::Decode(e)),
         }
     }
 
-    if let Some(ef) = expected_frames {
-        assert_eq!(ef, frames_collected);
-        println!("OK: Frames collected == expected_frames")
+    match expected_frames {
+        Some(ef) if ef != frames_collected => {
+            eprintln!("Warning: expected {ef} frames but decoded {frames_collected}")
+        }
+        Some(_) => println!("OK: Frames collected == expected_frames"),
+        None => (),
     }
 
-    let samples = samples_interleaved
-        .chunks(num_channels)
-        .map(|frame| frame.iter().sum())
-        .collect();
-
-    (samples, frames_collected)
+    Ok(Decoded {
+        frames,
+        sample_rate,
+        bpm,
+    })
 }
@@ -1,3 +1,8 @@
+use serde::{Deserialize, Serialize};
+
+/// Slots on the FX page, each holding a full set of `Params` that steps can recall
+pub const PRESET_SLOTS: usize = 8;
+
 /// A 90s Zoom multi-effects/NIN inspired digital distortion
 
 #[derive(Debug, Clone)]
@@ -19,6 +24,8 @@ impl Default for Destruction {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Params {
     pub pregain: f32,
     pub postgain: f32,
@@ -27,6 +34,23 @@ pub struct Params {
     pub resolution: f32,
     pub noise_amount: f32,
     pub feedback: f32,
+    // Wet/dry balance, 0 leaves the signal untouched
+    pub mix: f32,
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Self {
+            pregain: 4.,
+            postgain: 1.,
+            bit_depth: 32,
+            downsample_factor: 2,
+            resolution: 32.,
+            noise_amount: 0.1,
+            feedback: 0.1,
+            mix: 1.,
+        }
+    }
 }
 
 impl Params {
@@ -40,6 +64,30 @@ impl Params {
             resolution: 16.,
             noise_amount: 0.04,
             feedback: 0.3,
+            mix: 1.,
+        }
+    }
+
+    /// What the slots hold before they're edited, the default with the NIN preset next to it
+    pub fn presets() -> [Self; PRESET_SLOTS] {
+        let mut presets = [Self::default(); PRESET_SLOTS];
+        presets[1] = Self::nin();
+        presets
+    }
+
+    /// Moves `amount` of the way to `target`. The bit depth and downsampling only come in whole
+    /// steps, so they jump straight there.
+    pub fn smooth(self, target: Self, amount: f32) -> Self {
+        let towards = |from: f32, to: f32| from + (to - from) * amount;
+        Self {
+            pregain: towards(self.pregain, target.pregain),
+            postgain: towards(self.postgain, target.postgain),
+            bit_depth: target.bit_depth,
+            downsample_factor: target.downsample_factor,
+            resolution: towards(self.resolution, target.resolution),
+            noise_amount: towards(self.noise_amount, target.noise_amount),
+            feedback: towards(self.feedback, target.feedback),
+            mix: towards(self.mix, target.mix),
         }
     }
 }
@@ -54,13 +102,13 @@ impl Destruction {
 
         // Downsample the signal - only sample the input every `params.downsample_factor` ticks
         signal = if self.downsample_count == 0 {
-            let quantize_steps = (1 << params.bit_depth) as f32;
+            let quantize_steps = 2f32.powi(params.bit_depth as i32);
             (signal * quantize_steps).round() / quantize_steps
         } else {
             self.prev_sample
         };
 
-        self.downsample_count = (self.downsample_count + 1) % params.downsample_factor;
+        self.downsample_count = (self.downsample_count + 1) % params.downsample_factor.max(1);
         self.prev_sample = signal;
 
         // Add asymmetry
@@ -78,6 +126,7 @@ impl Destruction {
         signal += self.prev_sample * params.feedback;
 
         signal *= params.postgain;
-        signal.clamp(-1., 1.)
+        let wet = signal.clamp(-1., 1.);
+        input * (1. - params.mix) + wet * params.mix
     }
 }
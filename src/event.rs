@@ -0,0 +1,79 @@
+//! Events from the sequencer to the sampler, timestamped against the frames the audio thread has
+//! rendered and passed over a lock-free ring so the audio thread never allocates or locks
+
+use super::{
+    destruction,
+    sampler::{Step, StepBuilder},
+};
+
+// Room for plenty of steps scheduled ahead plus whatever the grid sends in between
+pub const EVENT_QUEUE_LEN: usize = 1024;
+
+pub type EventSender = rtrb::Producer<Event>;
+pub type EventReceiver = rtrb::Consumer<Event>;
+
+pub fn queue() -> (EventSender, EventReceiver) {
+    rtrb::RingBuffer::new(EVENT_QUEUE_LEN)
+}
+
+/// Something for the sampler to do once it has rendered up to `frame`. Swing, nudges and patterns
+/// at different divisions mean events don't arrive in frame order, so each is acted on at its own
+/// frame. Events due on the same frame are acted on in the order they were sent.
+#[derive(Debug, Clone, Copy)]
+pub struct Event {
+    pub frame: u64,
+    pub kind: EventKind,
+}
+
+impl Event {
+    pub fn at(frame: u64, kind: EventKind) -> Self {
+        Self { frame, kind }
+    }
+
+    /// Acted on as soon as the audio thread sees it
+    pub fn now(kind: EventKind) -> Self {
+        Self::at(0, kind)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum EventKind {
+    Trigger(StepBuilder),
+    /// Silence every voice
+    Stop,
+    /// Silence every voice and drop everything sent before this that's still waiting for its
+    /// frame, for when the sequencer stops with steps scheduled ahead
+    Flush,
+    Param(Param),
+    /// The sequencer's tempo from this frame on
+    Tempo(u32),
+}
+
+impl From<Step> for EventKind {
+    fn from(step: Step) -> Self {
+        match step {
+            Step::On(step) => Self::Trigger(step),
+            Step::Off => Self::Stop,
+        }
+    }
+}
+
+/// Edits to the sampler's state outside of the sequence
+#[derive(Debug, Clone, Copy)]
+pub enum Param {
+    /// Re-cut a sample into `count` equal slices
+    Slices { sample: usize, count: usize },
+    /// Move a slice's start point by `frames`, dragging the end of the slice before it along
+    Nudge {
+        sample: usize,
+        slice: usize,
+        frames: isize,
+    },
+    /// Store `params` in one of the distortion's preset slots
+    Preset {
+        slot: usize,
+        params: destruction::Params,
+    },
+    /// Glide the distortion over to a preset slot
+    Recall(usize),
+}
@@ -0,0 +1,100 @@
+//! A resonant multimode filter, the trapezoidal state-variable filter from Andrew Simper's
+//! "Linear Trap Integrated SVF" paper. It stays stable with its cutoff swept every frame, which
+//! the filter envelope does.
+
+use serde::{Deserialize, Serialize};
+use std::{f32::consts::PI, str::FromStr};
+
+pub const MIN_CUTOFF: f32 = 20.;
+pub const MAX_CUTOFF: f32 = 20_000.;
+// Self-oscillates at 1, so stop just short of it
+const MAX_RESONANCE: f32 = 0.98;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum Mode {
+    #[default]
+    Off,
+    Lowpass,
+    Highpass,
+    Bandpass,
+    Notch,
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Self::Off),
+            "lowpass" => Ok(Self::Lowpass),
+            "highpass" => Ok(Self::Highpass),
+            "bandpass" => Ok(Self::Bandpass),
+            "notch" => Ok(Self::Notch),
+            _ => Err(format!(
+                "Unknown filter mode '{s}', expected off, lowpass, highpass, bandpass or notch"
+            )),
+        }
+    }
+}
+
+/// The filter every voice plays through, unless a step brings its own cutoff
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub mode: Mode,
+    // In Hz
+    pub cutoff: f32,
+    // From 0, a gentle slope, to 1, ringing at the cutoff
+    pub resonance: f32,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            mode: Mode::default(),
+            cutoff: MAX_CUTOFF,
+            resonance: 0.,
+        }
+    }
+}
+
+/// One channel of filter state
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Svf {
+    ic1eq: f32,
+    ic2eq: f32,
+}
+
+impl Svf {
+    pub fn tick(
+        &mut self,
+        input: f32,
+        mode: Mode,
+        cutoff: f32,
+        resonance: f32,
+        sample_rate: f32,
+    ) -> f32 {
+        let cutoff = cutoff.clamp(MIN_CUTOFF, MAX_CUTOFF.min(sample_rate * 0.49));
+        let g = (PI * cutoff / sample_rate).tan();
+        let k = 2. - 2. * resonance.clamp(0., MAX_RESONANCE);
+        let a1 = 1. / (1. + g * (g + k));
+        let a2 = g * a1;
+        let a3 = g * a2;
+
+        let v3 = input - self.ic2eq;
+        let v1 = a1 * self.ic1eq + a2 * v3;
+        let v2 = self.ic2eq + a2 * self.ic1eq + a3 * v3;
+        self.ic1eq = 2. * v1 - self.ic1eq;
+        self.ic2eq = 2. * v2 - self.ic2eq;
+
+        let (low, band) = (v2, v1);
+        let high = input - k * band - low;
+        match mode {
+            Mode::Off => input,
+            Mode::Lowpass => low,
+            Mode::Highpass => high,
+            Mode::Bandpass => band,
+            Mode::Notch => low + high,
+        }
+    }
+}
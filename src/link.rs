@@ -0,0 +1,292 @@
+//! Link-style tempo and beat sharing between apps on the same machine. Peers multicast their
+//! session's timeline over UDP, a tempo and the moment beat zero fell on, and everyone in the
+//! session plays to the same one. Times are microseconds on the system clock, which everything
+//! on the host agrees on.
+
+use super::metro::{MAX_BPM, MIN_BPM};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    net::{Ipv4Addr, SocketAddrV4, UdpSocket},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+const GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 70, 83);
+const PORT: u16 = 20_909;
+const MAGIC: &[u8; 4] = b"FSeq";
+const PROTOCOL_VERSION: u8 = 1;
+const MESSAGE_LEN: usize = 41;
+// Timelines are announced this often so new peers pick them up and a lost packet doesn't matter
+const ANNOUNCE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Microseconds since the epoch on the system clock
+pub fn system_micros() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_micros() as i64)
+}
+
+/// A tempo and where beat zero fell, which together say where every beat lands
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timeline {
+    pub bpm: u32,
+    origin: i64,
+}
+
+impl Timeline {
+    fn beat_micros(&self) -> f64 {
+        60e6 / self.bpm as f64
+    }
+
+    /// The (fractional) beat at `micros`
+    pub fn beat_at(&self, micros: i64) -> f64 {
+        (micros - self.origin) as f64 / self.beat_micros()
+    }
+
+    /// When `beat` lands, in microseconds
+    pub fn micros_at(&self, beat: f64) -> f64 {
+        self.origin as f64 + beat * self.beat_micros()
+    }
+
+    /// The same timeline at a new tempo, with the beat at `now` left where it is
+    fn with_bpm(self, bpm: u32, now: i64) -> Self {
+        let beat = self.beat_at(now);
+        let bpm = bpm.max(1);
+        Self {
+            bpm,
+            origin: now - (beat * 60e6 / bpm as f64).round() as i64,
+        }
+    }
+}
+
+/// One peer's announcement of its session's timeline
+#[derive(Debug, Clone, Copy)]
+struct Message {
+    peer: u64,
+    // When the session was founded, the oldest one wins when two meet
+    session: i64,
+    // When the timeline last changed, the newest change wins within a session
+    changed: i64,
+    timeline: Timeline,
+}
+
+impl Message {
+    fn encode(&self) -> [u8; MESSAGE_LEN] {
+        let mut bytes = [0; MESSAGE_LEN];
+        bytes[..4].copy_from_slice(MAGIC);
+        bytes[4] = PROTOCOL_VERSION;
+        bytes[5..13].copy_from_slice(&self.peer.to_le_bytes());
+        bytes[13..21].copy_from_slice(&self.session.to_le_bytes());
+        bytes[21..29].copy_from_slice(&self.changed.to_le_bytes());
+        bytes[29..33].copy_from_slice(&self.timeline.bpm.to_le_bytes());
+        bytes[33..41].copy_from_slice(&self.timeline.origin.to_le_bytes());
+        bytes
+    }
+
+    /// Anything that isn't one of ours, from another app or a different protocol version, is
+    /// ignored, as is a tempo the metronome can't play
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != MESSAGE_LEN || &bytes[..4] != MAGIC || bytes[4] != PROTOCOL_VERSION {
+            return None;
+        }
+        let u64_at = |at: usize| u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap());
+        let bpm = u32::from_le_bytes(bytes[29..33].try_into().unwrap());
+        if !(MIN_BPM..=MAX_BPM).contains(&bpm) {
+            return None;
+        }
+
+        Some(Self {
+            peer: u64_at(5),
+            session: u64_at(13) as i64,
+            changed: u64_at(21) as i64,
+            timeline: Timeline {
+                bpm,
+                origin: u64_at(33) as i64,
+            },
+        })
+    }
+}
+
+/// This app's place in a session, founded on its own timeline until it hears from an older one
+pub struct Session {
+    socket: UdpSocket,
+    peer: u64,
+    session: i64,
+    changed: i64,
+    timeline: Timeline,
+    last_announce: Option<Instant>,
+}
+
+impl Session {
+    pub fn join(bpm: u32) -> Result<Self, String> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))
+            .map_err(|e| e.to_string())?;
+        // Every instance on the host listens on the same port
+        socket.set_reuse_address(true).map_err(|e| e.to_string())?;
+        socket
+            .bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, PORT).into())
+            .map_err(|e| e.to_string())?;
+        socket
+            .join_multicast_v4(&GROUP, &Ipv4Addr::UNSPECIFIED)
+            .map_err(|e| e.to_string())?;
+        socket
+            .set_multicast_loop_v4(true)
+            .map_err(|e| e.to_string())?;
+        socket.set_nonblocking(true).map_err(|e| e.to_string())?;
+
+        let now = system_micros();
+        Ok(Self {
+            socket: socket.into(),
+            peer: (std::process::id() as u64) << 32 ^ now as u64,
+            session: now,
+            changed: now,
+            timeline: Timeline {
+                bpm: bpm.max(1),
+                origin: now,
+            },
+            last_announce: None,
+        })
+    }
+
+    /// Identifies the session, changes when this app joins another
+    pub fn id(&self) -> i64 {
+        self.session
+    }
+
+    pub fn timeline(&self) -> Timeline {
+        self.timeline
+    }
+
+    /// Changes the tempo for everyone in the session, from the beat that's playing now
+    pub fn set_bpm(&mut self, bpm: u32) {
+        let now = system_micros();
+        self.timeline = self.timeline.with_bpm(bpm, now);
+        self.changed = now;
+        self.announce();
+    }
+
+    /// Takes in the other peers' announcements and makes ours when it's due. Returns whether
+    /// the timeline changed.
+    pub fn poll(&mut self) -> bool {
+        let before = (self.session, self.timeline);
+        let mut buf = [0; MESSAGE_LEN + 1];
+        while let Ok(len) = self.socket.recv(&mut buf) {
+            if let Some(message) = Message::decode(&buf[..len]) {
+                self.merge(message);
+            }
+        }
+
+        if self
+            .last_announce
+            .is_none_or(|last| last.elapsed() >= ANNOUNCE_INTERVAL)
+        {
+            self.announce();
+        }
+
+        (self.session, self.timeline) != before
+    }
+
+    fn merge(&mut self, message: Message) {
+        if message.peer == self.peer {
+            return;
+        }
+        let joining = message.session < self.session;
+        let newer = message.session == self.session && message.changed > self.changed;
+        if joining || newer {
+            self.session = message.session;
+            self.changed = message.changed;
+            self.timeline = message.timeline;
+        }
+    }
+
+    fn announce(&mut self) {
+        let message = Message {
+            peer: self.peer,
+            session: self.session,
+            changed: self.changed,
+            timeline: self.timeline,
+        };
+        if let Err(e) = self
+            .socket
+            .send_to(&message.encode(), SocketAddrV4::new(GROUP, PORT))
+        {
+            println!("Session announce failed: {e}");
+        }
+        self.last_announce = Some(Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message() -> Message {
+        Message {
+            peer: 0x0123_4567_89AB_CDEF,
+            session: 1_700_000_000_000_000,
+            changed: -42,
+            timeline: Timeline {
+                bpm: 174,
+                origin: 1_700_000_000_123_456,
+            },
+        }
+    }
+
+    #[test]
+    fn messages_survive_the_wire() {
+        let sent = message();
+        let received = Message::decode(&sent.encode()).unwrap();
+        assert_eq!(received.peer, sent.peer);
+        assert_eq!(received.session, sent.session);
+        assert_eq!(received.changed, sent.changed);
+        assert_eq!(received.timeline, sent.timeline);
+    }
+
+    #[test]
+    fn foreign_messages_are_ignored() {
+        let bytes = message().encode();
+        assert!(Message::decode(&bytes[..MESSAGE_LEN - 1]).is_none());
+
+        let mut other_app = bytes;
+        other_app[0] = b'X';
+        assert!(Message::decode(&other_app).is_none());
+
+        let mut other_version = bytes;
+        other_version[4] = PROTOCOL_VERSION + 1;
+        assert!(Message::decode(&other_version).is_none());
+
+        let no_tempo = Message {
+            timeline: Timeline { bpm: 0, origin: 0 },
+            ..message()
+        };
+        assert!(Message::decode(&no_tempo.encode()).is_none());
+
+        for bpm in [MIN_BPM - 1, MAX_BPM + 1, u32::MAX] {
+            let unplayable = Message {
+                timeline: Timeline { bpm, origin: 0 },
+                ..message()
+            };
+            assert!(Message::decode(&unplayable.encode()).is_none());
+        }
+        for bpm in [MIN_BPM, MAX_BPM] {
+            let playable = Message {
+                timeline: Timeline { bpm, origin: 0 },
+                ..message()
+            };
+            assert!(Message::decode(&playable.encode()).is_some());
+        }
+    }
+
+    #[test]
+    fn tempo_changes_keep_the_current_beat() {
+        let timeline = Timeline {
+            bpm: 120,
+            origin: 0,
+        };
+        let now = 10_000_000;
+        let faster = timeline.with_bpm(180, now);
+        // Origins are whole microseconds, so the beat can move by up to one
+        let drift = faster.beat_at(now) - timeline.beat_at(now);
+        assert!(drift.abs() <= 1. / faster.beat_micros());
+        assert_eq!(faster.micros_at(faster.beat_at(now)).round() as i64, now);
+    }
+}
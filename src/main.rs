@@ -1,37 +1,256 @@
 mod app;
+mod cli;
 mod common;
 mod decode;
 mod destruction;
+mod event;
+mod filter;
+mod link;
 mod metro;
+mod midi;
+mod onset;
+mod project;
+mod render;
+mod resample;
 mod sampler;
+mod sequence;
+mod slices;
 mod stream;
+mod tempo;
 mod widgets;
 use app::App;
+use clap::Parser;
 use cpal::traits::StreamTrait;
-use sampler::{Sampler, Step};
-use std::path::Path;
-
-fn main() -> std::io::Result<()> {
-    std::env::set_var("RUST_BACKTRACE", "1");
-    let path = Path::new("amen.wav");
-    let (samples, _frames_collected) = decode::decode(path);
-    let total_len = samples.len();
-    println!("Got {} samples", total_len);
-    let (sender, receiver) = std::sync::mpsc::channel::<Step>();
-    let sample_player = Sampler::new(samples, receiver);
-
-    let stream = stream::setup(sample_player).unwrap();
-    stream.play().unwrap();
+use project::{Project, ProjectSample};
+use sampler::{Sample, Sampler};
+use slices::SliceMap;
+use std::path::PathBuf;
+
+// Loaded when neither the command line nor a project names any samples
+const DEFAULT_SAMPLE: &str = "amen.wav";
+
+fn run(args: cli::Args) -> Result<(), String> {
+    let mut project = match &args.project {
+        Some(path) if path.exists() => {
+            println!("Opening project {}", path.display());
+            Project::load(path).map_err(|e| format!("{}: {e}", path.display()))?
+        }
+        _ => Project::default(),
+    };
+    project.bpm = args
+        .bpm
+        .unwrap_or(project.bpm)
+        .clamp(metro::MIN_BPM, metro::MAX_BPM);
+    project.swing = args
+        .swing
+        .unwrap_or(project.swing)
+        .clamp(0., metro::MAX_SWING);
+    project.filter = filter::Settings {
+        mode: args.filter.unwrap_or(project.filter.mode),
+        cutoff: args
+            .cutoff
+            .unwrap_or(project.filter.cutoff)
+            .clamp(filter::MIN_CUTOFF, filter::MAX_CUTOFF),
+        resonance: args
+            .resonance
+            .unwrap_or(project.filter.resonance)
+            .clamp(0., 1.),
+    };
+
+    // Samples on the command line replace the project's, and so do their slice points
+    let saved_slices = args.samples.is_empty() && !project.samples.is_empty();
+    if !saved_slices {
+        let paths = match args.samples.as_slice() {
+            [] => vec![PathBuf::from(DEFAULT_SAMPLE)],
+            paths => paths.to_vec(),
+        };
+        project.samples = paths
+            .into_iter()
+            .map(|path| ProjectSample {
+                path,
+                slices: Vec::new(),
+                bpm: None,
+            })
+            .collect();
+    }
+    let paths: Vec<PathBuf> = project
+        .samples
+        .iter()
+        .map(|sample| sample.path.clone())
+        .collect();
+
+    let mut bank = Vec::with_capacity(paths.len());
+    for path in &paths {
+        let decoded = decode::decode(path).map_err(|e| format!("{}: {e}", path.display()))?;
+        println!(
+            "Got {} frames from {}",
+            decoded.frames.len(),
+            path.display()
+        );
+        bank.push(decoded);
+    }
+
+    let (sender, receiver) = event::queue();
+    let (live_sender, live_receiver) = event::queue();
+    let load = |sample_rate: u32| {
+        let bank = bank
+            .into_iter()
+            .zip(&paths)
+            .enumerate()
+            .map(|(idx, (decoded, path))| {
+                let frames = resample::resample(
+                    decoded.frames,
+                    decoded.sample_rate,
+                    sample_rate,
+                    args.resample,
+                );
+                let onsets = onset::detect(&frames, sample_rate, args.sensitivity);
+                let slices = match project.slice_map(idx, frames.len(), sample_rate) {
+                    Some(slices) => slices,
+                    None if args.onsets => {
+                        let slices = SliceMap::from_onsets(frames.len(), &onsets);
+                        println!("Detected {} slices in {}", slices.len(), path.display());
+                        slices
+                    }
+                    None => SliceMap::equal(frames.len(), args.slices),
+                };
+
+                // The project's tempo for the sample wins over its tags, then its name
+                let given = project.samples[idx]
+                    .bpm
+                    .or(decoded.bpm)
+                    .or_else(|| tempo::from_name(path));
+                let tempo = match given {
+                    Some(bpm) => tempo::LoopTempo::from_bpm(bpm, frames.len(), sample_rate),
+                    None => tempo::detect(frames.len(), sample_rate, &onsets),
+                };
+                println!(
+                    "{} is {:.1} BPM over {} bars",
+                    path.display(),
+                    tempo.bpm,
+                    tempo.bars()
+                );
+                Sample::new(frames, slices, tempo.bpm)
+            })
+            .collect();
+        let sample_player = Sampler::new(bank, receiver)
+            .with_live_events(live_receiver)
+            .with_filter(project.filter)
+            .with_presets(project.presets(), project.preset)
+            .with_voices(args.voices);
+        if args.poly {
+            sample_player.with_choke_groups(Vec::new())
+        } else {
+            sample_player
+        }
+    };
+
+    if let Some(cli::Command::Render {
+        out,
+        bars,
+        sample_rate,
+    }) = &args.command
+    {
+        let settings = render::RenderSettings {
+            sample_rate: *sample_rate,
+            bpm: project.bpm,
+            swing: project.swing,
+            bars: *bars,
+            chain: project.chain,
+        };
+        println!(
+            "Rendering {} bars at {} BPM, {}Hz to {}",
+            settings.bars,
+            settings.bpm,
+            settings.sample_rate,
+            out.display()
+        );
+
+        let sequence = if project.steps.is_empty() {
+            render::default_sequence()
+        } else {
+            project.patterns()
+        };
+        let sample_player = load(settings.sample_rate);
+        let frames = render::render(sample_player, sender, &sequence, &settings);
+        return render::write_wav(out, &frames, settings.sample_rate);
+    }
+
+    let output = stream::Output::find(args.device.as_deref())?;
+    let sample_rate = output.sample_rate();
+    let sample_player = load(sample_rate);
+    let slice_maps = sample_player.slice_maps();
+    let clock = metro::Clock::default();
+    let stream = stream::setup(sample_player, output, clock.clone())?;
+    stream.play().map_err(|e| e.to_string())?;
+
+    let note_in = if args.midi_in {
+        Some(midi::NoteIn::open()?)
+    } else {
+        None
+    };
+    let clock_in = match args.midi_clock {
+        Some(midi::ClockSync::In) => Some(midi::ClockIn::open()?),
+        _ => None,
+    };
+    if args.link && args.midi_clock == Some(midi::ClockSync::In) {
+        return Err("Cannot follow MIDI clock and a session at once".to_string());
+    }
+    let session = if args.link {
+        Some(link::Session::join(project.bpm)?)
+    } else {
+        None
+    };
+    let midi_out = if args.midi_clock == Some(midi::ClockSync::Out) || args.midi_channel.is_some() {
+        Some(midi::MidiOut::open(clock.clone(), sample_rate)?)
+    } else {
+        None
+    };
+    let clock_out = midi_out
+        .clone()
+        .filter(|_| args.midi_clock == Some(midi::ClockSync::Out));
+    let notes = midi_out
+        .zip(args.midi_channel)
+        .map(|(out, channel)| midi::NoteOut::new(out, channel));
+
+    let project_path = args
+        .project
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(project::DEFAULT_PROJECT));
     match monome::Monome::enumerate_devices().as_deref() {
-        Ok([grid]) => match App::new(grid, sender) {
-            Ok(state) => state.run(),
+        Ok([grid]) => match App::new(
+            grid,
+            sender,
+            &project,
+            slice_maps,
+            sample_rate,
+            clock,
+            project_path,
+        ) {
+            Ok(mut state) => {
+                state = state.with_live_events(live_sender);
+                if let Some(notes) = notes {
+                    state = state.with_notes(notes, args.midi_only);
+                }
+                if let Some(note_in) = note_in {
+                    state = state.with_note_in(note_in);
+                }
+                state.run(clock_in, clock_out, session)
+            }
             Err(e) => {
                 println!("Setup failed: {e}");
             }
         },
         Ok(_) => println!("Grid not found :3"),
-        Err(e) => panic!("Monome error: {e}"),
+        Err(e) => return Err(format!("Monome error: {e}")),
     }
 
     Ok(())
 }
+
+fn main() {
+    if let Err(e) = run(cli::Args::parse()) {
+        eprintln!("Error: {e}");
+        std::process::exit(1);
+    }
+}
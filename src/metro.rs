@@ -1,39 +1,349 @@
-use std::time::{Duration, Instant};
+use super::{
+    link::{self, Session},
+    midi::{ClockIn, ClockMessage, MidiOut},
+};
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
-const LINES_PER_BAR: usize = 4;
+/// Clock pulses per beat, enough to land every step division on a whole pulse
+pub const PPQN: u64 = 24;
+pub const BEATS_PER_BAR: u64 = 4;
+// Far enough ahead to drag the longest step, an eighth, early by `MAX_NUDGE`
+pub const EARLY_PULSES: u64 = PPQN / 4;
+// How far ahead of the audio thread steps are scheduled, enough to cover an output buffer and
+// the time spent polling the grid
+const LOOKAHEAD_SECONDS: f64 = 0.05;
 
+// Furthest a step can be pushed or dragged off the grid, and how far off-beats can swing, in
+// fractions of a step
+pub const MAX_NUDGE: f32 = 0.5;
+pub const MAX_SWING: f32 = 0.5;
+
+pub const MIN_BPM: u32 = 40;
+pub const MAX_BPM: u32 = 300;
+// Taps further apart than this start a new tempo rather than averaging into the last one
+const TAP_TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_TAPS: usize = 5;
+// How much each tick of incoming MIDI clock moves the tempo estimate, lower is smoother
+const FOLLOW_SMOOTHING: f64 = 0.1;
+
+/// Length of one clock pulse in (fractional) sample frames
+pub fn frames_per_pulse(bpm: u32, sample_rate: u32) -> f64 {
+    let beat_frames = sample_rate as f64 * 60. / bpm as f64;
+    beat_frames / PPQN as f64
+}
+
+/// The tempo, to the nearest BPM, that has pulses `pulse_frames` long
+fn bpm_of_pulse(pulse_frames: f64, sample_rate: u32) -> u32 {
+    let beat_frames = pulse_frames * PPQN as f64;
+    ((sample_rate as f64 * 60. / beat_frames).round() as u32).clamp(MIN_BPM, MAX_BPM)
+}
+
+/// The number of frames the audio thread has rendered, shared with whoever schedules against it
+#[derive(Debug, Clone, Default)]
+pub struct Clock(Arc<AtomicU64>);
+
+impl Clock {
+    pub fn now(&self) -> u64 {
+        self.0.load(Ordering::Acquire)
+    }
+
+    pub fn set(&self, frame: u64) {
+        self.0.store(frame, Ordering::Release)
+    }
+}
+
+/// Extrapolates the audio thread's position between buffers from the wall clock, for sending
+/// and receiving things in real time rather than a buffer at a time
+pub struct ClockFollower {
+    clock: Clock,
+    sample_rate: f64,
+    last_frame: u64,
+    seen: Instant,
+}
+
+impl ClockFollower {
+    pub fn new(clock: Clock, sample_rate: u32) -> Self {
+        Self {
+            last_frame: clock.now(),
+            clock,
+            sample_rate: sample_rate as f64,
+            seen: Instant::now(),
+        }
+    }
+
+    /// Where the audio thread is now, in fractional frames
+    pub fn now(&mut self) -> f64 {
+        let frame = self.clock.now();
+        if frame != self.last_frame {
+            self.last_frame = frame;
+            self.seen = Instant::now();
+        }
+        self.last_frame as f64 + self.seen.elapsed().as_secs_f64() * self.sample_rate
+    }
+}
+
+/// Lines the metro's pulses up with a shared session's timeline. The metro's first pulse lands on
+/// a bar line of the session, so everyone in it plays in phase.
+struct SessionSync {
+    session: Session,
+    follower: ClockFollower,
+    sample_rate: f64,
+    // Which session the offset was worked out for
+    id: i64,
+    // The session pulse the metro's pulse zero lands on, always a whole number of bars
+    offset: i64,
+}
+
+impl SessionSync {
+    fn new(session: Session, clock: Clock, sample_rate: u32) -> Self {
+        Self {
+            id: session.id(),
+            session,
+            follower: ClockFollower::new(clock, sample_rate),
+            sample_rate: sample_rate as f64,
+            offset: 0,
+        }
+    }
+
+    /// Moves `pulse` to the first bar line it can still make on the session's timeline, keeping
+    /// its place in the bar
+    fn quantize(&mut self, pulse: u64, lookahead: u64) {
+        let earliest = link::system_micros() + (lookahead as f64 / self.sample_rate * 1e6) as i64;
+        let session_pulse = self.session.timeline().beat_at(earliest) * PPQN as f64;
+        let bar = (BEATS_PER_BAR * PPQN) as f64;
+        self.offset = ((session_pulse - pulse as f64) / bar).ceil() as i64 * bar as i64;
+        self.id = self.session.id();
+    }
+
+    /// Where `pulse` lands on the audio thread's clock
+    fn pulse_frame(&mut self, pulse: u64) -> f64 {
+        let beat = (pulse as i64 + self.offset) as f64 / PPQN as f64;
+        let micros = self.session.timeline().micros_at(beat);
+        let now = link::system_micros();
+        self.follower.now() + (micros - now as f64) / 1e6 * self.sample_rate
+    }
+}
+
+/// A clock pulse, counted from when the sequencer started, and where it lands in frames on the
+/// audio thread's clock
+#[derive(Debug, Clone, Copy)]
+pub struct PulseTime {
+    pub pulse: u64,
+    pub start: f64,
+    pub len: f64,
+}
+
+/// Where a step lands on the grid, in frames on the audio thread's clock
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StepTime {
+    pub start: f64,
+    pub len: f64,
+}
+
+impl StepTime {
+    /// The frame step `index` plays on, with off-beats pushed late by `swing` and the step's own
+    /// `nudge` on top, both in fractions of a step
+    pub fn frame(&self, index: usize, swing: f32, nudge: f32) -> u64 {
+        let swing = if index % 2 == 1 {
+            swing.clamp(0., MAX_SWING)
+        } else {
+            0.
+        };
+        let offset = swing + nudge.clamp(-MAX_NUDGE, MAX_NUDGE);
+        (self.start + offset as f64 * self.len).round().max(0.) as u64
+    }
+}
+
+/// Averages the time between taps into a tempo
+#[derive(Debug, Default)]
+pub struct TapTempo {
+    taps: VecDeque<Instant>,
+}
+
+impl TapTempo {
+    /// Returns the tapped tempo once there have been at least two taps in a row
+    pub fn tap(&mut self) -> Option<u32> {
+        let now = Instant::now();
+        if self
+            .taps
+            .back()
+            .is_some_and(|&last| now.duration_since(last) > TAP_TIMEOUT)
+        {
+            self.taps.clear();
+        }
+        if self.taps.len() == MAX_TAPS {
+            self.taps.pop_front();
+        }
+        self.taps.push_back(now);
+
+        let first = self.taps.front()?;
+        let intervals = self.taps.len() as u32 - 1;
+        let beat = now.duration_since(*first).checked_div(intervals)?;
+        let bpm = (60. / beat.as_secs_f64()).round() as u32;
+        Some(bpm.clamp(MIN_BPM, MAX_BPM))
+    }
+}
+
+/// Calls `tick` once per pulse with where the pulse lands, a little before the audio thread gets
+/// there and early enough to drag the longest step ahead of the grid. Pulses are counted in
+/// fractional frames so they never drift.
 pub struct Metro<State> {
-    interval: Duration,
-    last_execution: Instant,
+    clock: Clock,
+    sample_rate: u32,
+    bpm: u32,
+    pulse_frames: f64,
+    lookahead: u64,
+    pulse: u64,
+    next_pulse: f64,
+    clock_in: Option<ClockIn>,
+    clock_out: Option<MidiOut>,
+    session: Option<Session>,
     state: State,
 }
 
 impl<State> Metro<State> {
-    pub fn new(bpm: u32, state: State) -> Self {
-        let bpm_ms = 60_000. / bpm as f32;
-        let interval_ms = bpm_ms / LINES_PER_BAR as f32;
-        let interval = Duration::from_millis(interval_ms as u64);
-        let last_execution = Instant::now();
+    pub fn new(bpm: u32, sample_rate: u32, clock: Clock, state: State) -> Self {
+        let lookahead = (sample_rate as f64 * LOOKAHEAD_SECONDS) as u64;
+        let next_pulse = (clock.now() + lookahead) as f64;
         Self {
-            interval,
-            last_execution,
+            clock,
+            sample_rate,
+            bpm,
+            pulse_frames: frames_per_pulse(bpm, sample_rate),
+            lookahead,
+            pulse: 0,
+            next_pulse,
+            clock_in: None,
+            clock_out: None,
+            session: None,
             state,
         }
     }
 
+    /// Plays to a shared session's timeline instead of keeping time itself
+    pub fn with_session(self, session: Session) -> Self {
+        Self {
+            session: Some(session),
+            ..self
+        }
+    }
+
+    /// Follows incoming MIDI clock instead of keeping time itself
+    pub fn with_clock_in(self, clock_in: ClockIn) -> Self {
+        Self {
+            clock_in: Some(clock_in),
+            ..self
+        }
+    }
+
+    /// Sends MIDI clock in time with the pulses
+    pub fn with_clock_out(self, clock_out: MidiOut) -> Self {
+        Self {
+            clock_out: Some(clock_out),
+            ..self
+        }
+    }
+
     fn is_ready(&self) -> bool {
-        Instant::now() >= self.last_execution + self.interval
+        let early = self.pulse_frames * EARLY_PULSES as f64;
+        (self.clock.now() + self.lookahead) as f64 + early >= self.next_pulse
     }
 
-    pub fn forever<Tick, HandleEvent>(mut self, mut tick: Tick, mut handle_event: HandleEvent)
-    where
-        Tick: FnMut(&mut State),
+    /// Moves the pulse that hasn't been scheduled yet to land one pulse at the new tempo after
+    /// the last one, so a change is heard on the very next step
+    fn set_bpm(&mut self, bpm: u32) {
+        let last_pulse = self.next_pulse - self.pulse_frames;
+        self.bpm = bpm;
+        self.pulse_frames = frames_per_pulse(bpm, self.sample_rate);
+        self.next_pulse = last_pulse + self.pulse_frames;
+    }
+
+    /// Runs the sequencer, `bpm` is checked between pulses for tempo changes and `set_bpm` is
+    /// called when someone else in the session or the incoming clock changes it. `stop` is called
+    /// when the incoming clock stops, to drop the pulses already scheduled ahead, and `seek` with
+    /// the pulse it'll carry on from when it starts over or jumps.
+    pub fn forever<Tick, HandleEvent, Bpm, SetBpm, Stop, Seek>(
+        mut self,
+        mut tick: Tick,
+        mut handle_event: HandleEvent,
+        bpm: Bpm,
+        mut set_bpm: SetBpm,
+        stop: Stop,
+        seek: Seek,
+    ) where
+        Tick: FnMut(&mut State, PulseTime),
         HandleEvent: FnMut(&mut State) -> bool,
+        Bpm: Fn(&State) -> u32,
+        SetBpm: FnMut(&mut State, u32),
+        Stop: FnMut(&mut State),
+        Seek: FnMut(&mut State, u64),
     {
+        if let Some(clock_in) = self.clock_in.take() {
+            return self.follow(clock_in, tick, handle_event, set_bpm, stop, seek);
+        }
+
+        let mut sync = self
+            .session
+            .take()
+            .map(|session| SessionSync::new(session, self.clock.clone(), self.sample_rate));
+        if let Some(sync) = &mut sync {
+            sync.quantize(self.pulse, self.lookahead);
+            self.bpm = sync.session.timeline().bpm;
+            self.pulse_frames = frames_per_pulse(self.bpm, self.sample_rate);
+            self.next_pulse = sync.pulse_frame(self.pulse);
+            set_bpm(&mut self.state, self.bpm);
+        }
+
         loop {
+            let current_bpm = bpm(&self.state);
+            if current_bpm != self.bpm {
+                self.set_bpm(current_bpm);
+                if let Some(sync) = &mut sync {
+                    sync.session.set_bpm(current_bpm);
+                    self.next_pulse = sync.pulse_frame(self.pulse);
+                }
+            }
+
+            if let Some(sync) = &mut sync {
+                if sync.session.poll() {
+                    if sync.session.id() != sync.id {
+                        sync.quantize(self.pulse, self.lookahead);
+                    }
+                    let session_bpm = sync.session.timeline().bpm;
+                    if session_bpm != self.bpm {
+                        self.bpm = session_bpm;
+                        self.pulse_frames = frames_per_pulse(session_bpm, self.sample_rate);
+                        set_bpm(&mut self.state, session_bpm);
+                    }
+                    self.next_pulse = sync.pulse_frame(self.pulse);
+                }
+            }
+
             if self.is_ready() {
-                tick(&mut self.state);
-                self.last_execution = Instant::now();
+                let time = PulseTime {
+                    pulse: self.pulse,
+                    start: self.next_pulse,
+                    len: self.pulse_frames,
+                };
+                tick(&mut self.state, time);
+                if let Some(clock_out) = &self.clock_out {
+                    if self.pulse == 0 {
+                        clock_out.clock(time.start, ClockMessage::Start);
+                    }
+                    clock_out.clock(time.start, ClockMessage::Tick);
+                }
+                self.pulse += 1;
+                self.next_pulse = match &mut sync {
+                    Some(sync) => sync.pulse_frame(self.pulse),
+                    None => self.next_pulse + self.pulse_frames,
+                };
             } else {
                 'inner: while !self.is_ready() {
                     if handle_event(&mut self.state) {
@@ -45,4 +355,85 @@ impl<State> Metro<State> {
             }
         }
     }
+
+    /// Runs the sequencer off incoming MIDI clock. Each tick is a pulse, and pulses are scheduled
+    /// `EARLY_PULSES` ahead from the tempo the ticks have been arriving at, so nudges still work.
+    fn follow<Tick, HandleEvent, SetBpm, Stop, Seek>(
+        mut self,
+        clock_in: ClockIn,
+        mut tick: Tick,
+        mut handle_event: HandleEvent,
+        mut set_bpm: SetBpm,
+        mut stop: Stop,
+        mut seek: Seek,
+    ) where
+        Tick: FnMut(&mut State, PulseTime),
+        HandleEvent: FnMut(&mut State) -> bool,
+        SetBpm: FnMut(&mut State, u32),
+        Stop: FnMut(&mut State),
+        Seek: FnMut(&mut State, u64),
+    {
+        let mut follower = ClockFollower::new(self.clock.clone(), self.sample_rate);
+        let mut running = false;
+        let mut last_tick: Option<Instant> = None;
+        // The first pulse that hasn't been handed to `tick` yet
+        let mut next_scheduled = 0;
+
+        loop {
+            let Some((at, message)) = clock_in.try_recv() else {
+                handle_event(&mut self.state);
+                continue;
+            };
+
+            match message {
+                ClockMessage::Tick => {
+                    if let Some(last) = last_tick.replace(at) {
+                        let interval =
+                            at.duration_since(last).as_secs_f64() * self.sample_rate as f64;
+                        self.pulse_frames += (interval - self.pulse_frames) * FOLLOW_SMOOTHING;
+
+                        let bpm = bpm_of_pulse(self.pulse_frames, self.sample_rate);
+                        if bpm != self.bpm {
+                            self.bpm = bpm;
+                            set_bpm(&mut self.state, bpm);
+                        }
+                    }
+                    if !running {
+                        continue;
+                    }
+
+                    let now = follower.now() + self.lookahead as f64;
+                    for pulse in next_scheduled..=self.pulse + EARLY_PULSES {
+                        let time = PulseTime {
+                            pulse,
+                            start: now + (pulse - self.pulse) as f64 * self.pulse_frames,
+                            len: self.pulse_frames,
+                        };
+                        tick(&mut self.state, time);
+                    }
+                    next_scheduled = self.pulse + EARLY_PULSES + 1;
+                    self.pulse += 1;
+                }
+                ClockMessage::Start => {
+                    running = true;
+                    self.pulse = 0;
+                    next_scheduled = 0;
+                    seek(&mut self.state, 0);
+                }
+                // Pulses handed to `tick` before the stop aren't handed over again
+                ClockMessage::Continue => running = true,
+                ClockMessage::Stop => {
+                    if running {
+                        stop(&mut self.state);
+                    }
+                    running = false;
+                }
+                ClockMessage::SongPosition(sixteenths) => {
+                    self.pulse = sixteenths as u64 * PPQN / 4;
+                    next_scheduled = self.pulse;
+                    seek(&mut self.state, self.pulse);
+                }
+            }
+        }
+    }
 }
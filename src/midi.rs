@@ -0,0 +1,406 @@
+//! MIDI over virtual ports (ALSA sequencer ports on Linux), so drum machines, DAWs and other
+//! apps can connect to FerroSeq without it needing to know their port names
+
+use super::{
+    metro::{Clock, ClockFollower},
+    sampler::StepBuilder,
+};
+use midir::{
+    os::unix::{VirtualInput, VirtualOutput},
+    MidiInput, MidiInputConnection, MidiOutput,
+};
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    str::FromStr,
+    sync::mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    time::{Duration, Instant},
+};
+
+const CLIENT_NAME: &str = "FerroSeq";
+
+const CLOCK: u8 = 0xF8;
+const START: u8 = 0xFA;
+const CONTINUE: u8 = 0xFB;
+const STOP: u8 = 0xFC;
+const SONG_POSITION: u8 = 0xF2;
+const NOTE_ON: u8 = 0x90;
+const NOTE_OFF: u8 = 0x80;
+
+// Slice 0 plays the kick on General MIDI drum maps and the bottom left pad on most controllers
+const FIRST_NOTE: u8 = 36;
+const MAX_NOTE: u8 = 127;
+
+// How often the output thread checks whether the next message is due
+const MIDI_OUT_POLL: Duration = Duration::from_micros(250);
+
+/// Which way MIDI clock goes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClockSync {
+    /// Follow clock sent to FerroSeq's input port
+    In,
+    /// Send clock from FerroSeq's output port
+    Out,
+}
+
+impl FromStr for ClockSync {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "in" => Ok(Self::In),
+            "out" => Ok(Self::Out),
+            _ => Err(format!(
+                "Unknown MIDI clock direction '{s}', expected in or out"
+            )),
+        }
+    }
+}
+
+/// MIDI clock messages, ticks are 24 to the beat
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClockMessage {
+    Tick,
+    Start,
+    Continue,
+    Stop,
+    /// Sixteenths since the start of the song
+    SongPosition(u16),
+}
+
+impl ClockMessage {
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        match *bytes {
+            [CLOCK, ..] => Some(Self::Tick),
+            [START, ..] => Some(Self::Start),
+            [CONTINUE, ..] => Some(Self::Continue),
+            [STOP, ..] => Some(Self::Stop),
+            [SONG_POSITION, lsb, msb, ..] => {
+                Some(Self::SongPosition((msb as u16) << 7 | lsb as u16))
+            }
+            _ => None,
+        }
+    }
+
+    fn bytes(self) -> Vec<u8> {
+        match self {
+            Self::Tick => vec![CLOCK],
+            Self::Start => vec![START],
+            Self::Continue => vec![CONTINUE],
+            Self::Stop => vec![STOP],
+            Self::SongPosition(position) => {
+                vec![
+                    SONG_POSITION,
+                    (position & 0x7F) as u8,
+                    (position >> 7) as u8,
+                ]
+            }
+        }
+    }
+}
+
+/// Clock from another app or device, received on a virtual input port
+pub struct ClockIn {
+    // Messages stop arriving once this is dropped
+    _connection: MidiInputConnection<()>,
+    receiver: Receiver<(Instant, ClockMessage)>,
+}
+
+impl ClockIn {
+    pub fn open() -> Result<Self, String> {
+        let mut input = MidiInput::new(CLIENT_NAME).map_err(|e| e.to_string())?;
+        input.ignore(midir::Ignore::None);
+
+        let (sender, receiver) = mpsc::channel();
+        let connection = input
+            .create_virtual(
+                "clock in",
+                move |_, bytes, _| {
+                    if let Some(message) = ClockMessage::parse(bytes) {
+                        let _ = sender.send((Instant::now(), message));
+                    }
+                },
+                (),
+            )
+            .map_err(|e| e.to_string())?;
+
+        Ok(Self {
+            _connection: connection,
+            receiver,
+        })
+    }
+
+    /// The next message and when it arrived, if there is one
+    pub fn try_recv(&self) -> Option<(Instant, ClockMessage)> {
+        self.receiver.try_recv().ok()
+    }
+}
+
+/// A note played on a keyboard or pad controller
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NoteMessage {
+    pub note: u8,
+    pub velocity: u8,
+}
+
+impl NoteMessage {
+    /// Note ons on any channel, note offs are ignored since slices play out on their own
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        match *bytes {
+            [status, note, velocity, ..] if status & 0xF0 == NOTE_ON && velocity > 0 => {
+                Some(Self { note, velocity })
+            }
+            _ => None,
+        }
+    }
+
+    /// From 0 to 1, for `StepBuilder::with_velocity`
+    pub fn level(&self) -> f32 {
+        self.velocity as f32 / 127.
+    }
+}
+
+/// The note that plays `slice` of `sample`, counting up from note 36 through every slice of the
+/// first sample and on into the next. `slice_counts` are how many slices each sample in the bank
+/// is cut into. Slices past the sample's last wrap round the way the sampler plays them, and a
+/// sample slot with nothing in it has no note.
+pub fn slice_to_note(
+    sample: usize,
+    slice: usize,
+    slice_counts: impl IntoIterator<Item = usize>,
+) -> Option<u8> {
+    let mut earlier = 0;
+    for (idx, count) in slice_counts.into_iter().enumerate() {
+        if idx == sample {
+            if count == 0 {
+                return None;
+            }
+            return u8::try_from(FIRST_NOTE as usize + earlier + slice % count)
+                .ok()
+                .filter(|&note| note <= MAX_NOTE);
+        }
+        earlier += count;
+    }
+    None
+}
+
+/// The sample and slice `note` plays, the other way round from `slice_to_note`. Notes below 36 or
+/// past the last slice of the bank don't play anything.
+pub fn note_to_slice(
+    note: u8,
+    slice_counts: impl IntoIterator<Item = usize>,
+) -> Option<(usize, usize)> {
+    let mut slice = usize::from(note.checked_sub(FIRST_NOTE)?);
+    for (sample, count) in slice_counts.into_iter().enumerate() {
+        if slice < count {
+            return Some((sample, slice));
+        }
+        slice -= count;
+    }
+    None
+}
+
+/// Notes from a keyboard or pad controller, received on a virtual input port
+pub struct NoteIn {
+    _connection: MidiInputConnection<()>,
+    receiver: Receiver<NoteMessage>,
+}
+
+impl NoteIn {
+    pub fn open() -> Result<Self, String> {
+        let input = MidiInput::new(CLIENT_NAME).map_err(|e| e.to_string())?;
+
+        let (sender, receiver) = mpsc::channel();
+        let connection = input
+            .create_virtual(
+                "notes in",
+                move |_, bytes, _| {
+                    if let Some(message) = NoteMessage::parse(bytes) {
+                        let _ = sender.send(message);
+                    }
+                },
+                (),
+            )
+            .map_err(|e| e.to_string())?;
+
+        Ok(Self {
+            _connection: connection,
+            receiver,
+        })
+    }
+
+    pub fn try_recv(&self) -> Option<NoteMessage> {
+        self.receiver.try_recv().ok()
+    }
+}
+
+/// A message waiting for its frame, ties go out in the order they were queued
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Pending {
+    frame: u64,
+    order: u64,
+    bytes: Vec<u8>,
+}
+
+/// Everything FerroSeq sends, from one virtual output port. Messages are queued with the frame
+/// they belong on and sent from their own thread once the audio thread gets there, rather than
+/// when the metro schedules them.
+#[derive(Clone)]
+pub struct MidiOut {
+    sender: Sender<(u64, Vec<u8>)>,
+}
+
+impl MidiOut {
+    pub fn open(clock: Clock, sample_rate: u32) -> Result<Self, String> {
+        let output = MidiOutput::new(CLIENT_NAME).map_err(|e| e.to_string())?;
+        let mut connection = output.create_virtual("out").map_err(|e| e.to_string())?;
+
+        let (sender, receiver) = mpsc::channel::<(u64, Vec<u8>)>();
+        std::thread::spawn(move || {
+            let mut follower = ClockFollower::new(clock, sample_rate);
+            // Note offs are queued a step ahead of the note ons that follow them, so messages
+            // can't just go out in the order they arrive
+            let mut pending = BinaryHeap::new();
+            let mut order = 0;
+            loop {
+                match receiver.recv_timeout(MIDI_OUT_POLL) {
+                    Ok((frame, bytes)) => {
+                        pending.push(Reverse(Pending {
+                            frame,
+                            order,
+                            bytes,
+                        }));
+                        order += 1;
+                    }
+                    Err(RecvTimeoutError::Timeout) => (),
+                    Err(RecvTimeoutError::Disconnected) if pending.is_empty() => break,
+                    Err(RecvTimeoutError::Disconnected) => std::thread::sleep(MIDI_OUT_POLL),
+                }
+
+                let now = follower.now();
+                while pending
+                    .peek()
+                    .is_some_and(|Reverse(next)| next.frame as f64 <= now)
+                {
+                    let Some(Reverse(next)) = pending.pop() else {
+                        break;
+                    };
+                    if let Err(e) = connection.send(&next.bytes) {
+                        println!("MIDI out: {e}");
+                    }
+                }
+            }
+        });
+
+        Ok(Self { sender })
+    }
+
+    fn send(&self, frame: u64, bytes: Vec<u8>) {
+        let _ = self.sender.send((frame, bytes));
+    }
+
+    /// Queues `message` to go out when the audio thread reaches `frame`
+    pub fn clock(&self, frame: f64, message: ClockMessage) {
+        self.send(frame.round() as u64, message.bytes());
+    }
+}
+
+/// Sends steps out as notes on one channel, on the notes `slice_to_note` gives their slices
+pub struct NoteOut {
+    out: MidiOut,
+    // Zero based, as it goes on the wire
+    channel: u8,
+}
+
+impl NoteOut {
+    /// `channel` counts from 1, like on the gear it's plugged into
+    pub fn new(out: MidiOut, channel: u8) -> Self {
+        Self {
+            out,
+            channel: channel.clamp(1, 16) - 1,
+        }
+    }
+
+    /// Plays `note` at `step`'s velocity from `frame` for `len` frames
+    pub fn play(&self, frame: u64, len: f64, note: u8, step: &StepBuilder) {
+        let velocity = (step.velocity() * 127.).round().clamp(1., 127.) as u8;
+        self.out
+            .send(frame, vec![NOTE_ON | self.channel, note, velocity]);
+        self.out.send(
+            frame + len.round() as u64,
+            vec![NOTE_OFF | self.channel, note, 0],
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clock_messages_parse_what_they_send() {
+        let messages = [
+            ClockMessage::Tick,
+            ClockMessage::Start,
+            ClockMessage::Continue,
+            ClockMessage::Stop,
+            ClockMessage::SongPosition(0),
+            ClockMessage::SongPosition(300),
+            ClockMessage::SongPosition(0x3FFF),
+        ];
+        for message in messages {
+            assert_eq!(ClockMessage::parse(&message.bytes()), Some(message));
+        }
+    }
+
+    #[test]
+    fn song_position_is_two_seven_bit_bytes() {
+        // Bar 5 is 64 sixteenths in, 0x40 in the low byte
+        assert_eq!(
+            ClockMessage::parse(&[SONG_POSITION, 0x40, 0x00]),
+            Some(ClockMessage::SongPosition(64))
+        );
+        assert_eq!(
+            ClockMessage::parse(&[SONG_POSITION, 0x00, 0x01]),
+            Some(ClockMessage::SongPosition(128))
+        );
+        assert_eq!(ClockMessage::parse(&[SONG_POSITION, 0x00]), None);
+        assert_eq!(ClockMessage::parse(&[NOTE_ON, 36, 100]), None);
+        assert_eq!(ClockMessage::parse(&[]), None);
+    }
+
+    #[test]
+    fn only_sounding_note_ons_are_notes() {
+        assert_eq!(
+            NoteMessage::parse(&[NOTE_ON | 9, 38, 100]),
+            Some(NoteMessage {
+                note: 38,
+                velocity: 100
+            })
+        );
+        assert_eq!(NoteMessage::parse(&[NOTE_ON, 38, 0]), None);
+        assert_eq!(NoteMessage::parse(&[NOTE_OFF, 38, 64]), None);
+    }
+
+    #[test]
+    fn notes_count_on_through_each_sample() {
+        let counts = [3, 16, 5];
+        assert_eq!(slice_to_note(0, 0, counts), Some(36));
+        assert_eq!(slice_to_note(1, 0, counts), Some(39));
+        assert_eq!(slice_to_note(2, 4, counts), Some(59));
+        // Wrapped round like the sampler plays them, and nothing for empty or missing samples
+        assert_eq!(slice_to_note(0, 4, counts), Some(37));
+        assert_eq!(slice_to_note(2, 5, counts), Some(55));
+        assert_eq!(slice_to_note(3, 0, counts), None);
+        assert_eq!(slice_to_note(1, 0, [3, 0]), None);
+        assert_eq!(note_to_slice(35, counts), None);
+        assert_eq!(note_to_slice(60, counts), None);
+
+        for (sample, &count) in counts.iter().enumerate() {
+            for slice in 0..count {
+                let note = slice_to_note(sample, slice, counts).unwrap();
+                assert_eq!(note_to_slice(note, counts), Some((sample, slice)));
+            }
+        }
+    }
+}
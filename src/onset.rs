@@ -0,0 +1,121 @@
+//! Transient detection for slicing breaks that aren't cut evenly
+
+use super::{common::Frame, slices::MAX_SLICES};
+
+// Analysis hop and window, in seconds so detection behaves the same at any sample rate
+const HOP_SECONDS: f32 = 0.005;
+const WINDOW_HOPS: usize = 2;
+// Hops either side of a candidate that the adaptive threshold averages over
+const THRESHOLD_HOPS: usize = 10;
+// Hits closer together than this are treated as one
+const MIN_GAP_SECONDS: f32 = 0.06;
+
+pub const DEFAULT_SENSITIVITY: f32 = 0.5;
+// A slice always starts at the top of the buffer, so that leaves room for one less than the most
+// slices a sample can be cut into
+const MAX_ONSETS: usize = MAX_SLICES - 1;
+
+/// Finds onsets in `frames` from the rise in short-term energy, returning their positions in
+/// frames. `sensitivity` runs from 0 (only the hardest hits) to 1 (every ghost note). Past the
+/// number of slices a sample can hold, only the strongest are kept.
+pub fn detect(frames: &[Frame], sample_rate: u32, sensitivity: f32) -> Vec<usize> {
+    let hop = ((sample_rate as f32 * HOP_SECONDS) as usize).max(1);
+    let window = hop * WINDOW_HOPS;
+    if frames.len() < window {
+        return Vec::new();
+    }
+
+    // RMS of each window, mixed down to mono
+    let rms = frames
+        .windows(window)
+        .step_by(hop)
+        .map(|window| {
+            let energy = window
+                .iter()
+                .map(|[left, right]| ((left + right) * 0.5).powi(2))
+                .sum::<f32>();
+            (energy / window.len() as f32).sqrt()
+        })
+        .collect::<Vec<_>>();
+
+    // Onset detection function, only rises in level count
+    let mut flux = std::iter::once(0.)
+        .chain(rms.windows(2).map(|pair| (pair[1] - pair[0]).max(0.)))
+        .collect::<Vec<_>>();
+    let peak = flux.iter().copied().fold(0f32, f32::max);
+    if peak <= 0. {
+        return Vec::new();
+    }
+    flux.iter_mut().for_each(|value| *value /= peak);
+
+    let sensitivity = sensitivity.clamp(0., 1.);
+    let delta = 0.02 + (1. - sensitivity) * 0.3;
+    let min_gap = ((sample_rate as f32 * MIN_GAP_SECONDS) as usize / hop).max(1);
+
+    let mut onsets = Vec::new();
+    let mut last_onset: Option<usize> = None;
+    for idx in 0..flux.len() {
+        let lo = idx.saturating_sub(THRESHOLD_HOPS);
+        let hi = (idx + THRESHOLD_HOPS + 1).min(flux.len());
+        let neighbourhood = &flux[lo..hi];
+
+        let is_peak = neighbourhood.iter().all(|&value| value <= flux[idx]);
+        let mean = neighbourhood.iter().sum::<f32>() / neighbourhood.len() as f32;
+        let spaced = last_onset.is_none_or(|last| idx - last >= min_gap);
+
+        if is_peak && flux[idx] > mean * 1.5 + delta && spaced {
+            onsets.push((idx * hop, flux[idx]));
+            last_onset = Some(idx);
+        }
+    }
+
+    if onsets.len() > MAX_ONSETS {
+        onsets.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+        onsets.truncate(MAX_ONSETS);
+        onsets.sort_by_key(|&(onset, _)| onset);
+    }
+    onsets.into_iter().map(|(onset, _)| onset).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 48_000;
+
+    /// Silence with a short burst at each of `hits`, a position in frames and a level
+    fn clicks(len: usize, hits: &[(usize, f32)]) -> Vec<Frame> {
+        let mut frames = vec![[0., 0.]; len];
+        for &(at, level) in hits {
+            frames[at..at + 480].fill([level, level]);
+        }
+        frames
+    }
+
+    #[test]
+    fn finds_hits() {
+        let hits = [(4_800, 0.8), (24_000, 0.8), (36_000, 0.4)];
+        let onsets = detect(&clicks(48_000, &hits), SAMPLE_RATE, DEFAULT_SENSITIVITY);
+        assert_eq!(onsets.len(), hits.len());
+        for (onset, (hit, _)) in onsets.iter().zip(hits) {
+            assert!(onset.abs_diff(hit) <= 480, "{onset} isn't near {hit}");
+        }
+    }
+
+    #[test]
+    fn keeps_only_the_strongest_that_fit() {
+        // Every fourth hit is loud, and there are more quiet ones than slices
+        let hits = (1..=MAX_SLICES * 2)
+            .map(|idx| (idx * 4_800, if idx % 4 == 0 { 0.9 } else { 0.3 }))
+            .collect::<Vec<_>>();
+        let len = (MAX_SLICES * 2 + 2) * 4_800;
+        let onsets = detect(&clicks(len, &hits), SAMPLE_RATE, 1.);
+        assert_eq!(onsets.len(), MAX_ONSETS);
+        assert!(onsets.is_sorted());
+
+        let loud = hits.iter().filter(|(_, level)| *level > 0.5);
+        for &(hit, _) in loud {
+            assert!(onsets.iter().any(|onset| onset.abs_diff(hit) <= 480));
+        }
+    }
+}
@@ -0,0 +1,355 @@
+//! Project files - the sequence, samples, slice points and tempo saved as TOML
+
+use super::{
+    common::*,
+    destruction::{self, PRESET_SLOTS},
+    filter,
+    metro::{MAX_BPM, MIN_BPM},
+    sampler::{Step, StepBuilder},
+    sequence::{Division, Pattern},
+    slices::SliceMap,
+    tempo,
+};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// Bumped whenever the schema changes in a way older builds can't read, see `Project::migrate`
+pub const PROJECT_VERSION: u32 = 2;
+/// Where the grid's save key writes when no `--project` was given
+pub const DEFAULT_PROJECT: &str = "project.toml";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Project {
+    pub version: u32,
+    #[serde(default = "default_bpm")]
+    pub bpm: u32,
+    // Fraction of a step the off-beats are pushed late
+    #[serde(default)]
+    pub swing: f32,
+    #[serde(default)]
+    pub filter: filter::Settings,
+    // The distortion's preset slots, any left out keep their defaults, and the one it starts on
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub presets: Vec<destruction::Params>,
+    #[serde(default)]
+    pub preset: usize,
+    #[serde(default = "default_num_patterns")]
+    pub num_patterns: usize,
+    // Play the patterns one after another rather than all at once
+    #[serde(default)]
+    pub chain: bool,
+    // Patterns past the end of this play 16 sixteenths
+    #[serde(default)]
+    pub patterns: Vec<ProjectPattern>,
+    #[serde(default)]
+    pub samples: Vec<ProjectSample>,
+    // Only steps that are on are written, everything else is left off. Step `index` is
+    // `pattern * 16 + step`.
+    #[serde(default)]
+    pub steps: Vec<ProjectStep>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ProjectPattern {
+    pub length: usize,
+    pub division: Division,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ProjectSample {
+    pub path: PathBuf,
+    // Empty means the sample is cut by the command line's `--slices` or `--onsets`
+    #[serde(default)]
+    pub slices: Vec<ProjectSlice>,
+    // The tempo it was played at, worked out each time it's loaded when left out. Steps only
+    // follow it once they're set to one of the stretches that follow the tempo.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bpm: Option<f32>,
+}
+
+/// Slice start points are kept in seconds so they survive a change of output device
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ProjectSlice {
+    pub start: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub choke: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ProjectStep {
+    pub index: usize,
+    #[serde(flatten)]
+    pub step: StepBuilder,
+}
+
+fn default_bpm() -> u32 {
+    DEFAULT_BPM
+}
+
+fn default_num_patterns() -> usize {
+    DEFAULT_NUM_PATTERNS
+}
+
+impl Default for Project {
+    fn default() -> Self {
+        Self {
+            version: PROJECT_VERSION,
+            bpm: DEFAULT_BPM,
+            swing: 0.,
+            filter: filter::Settings::default(),
+            presets: Vec::new(),
+            preset: 0,
+            num_patterns: DEFAULT_NUM_PATTERNS,
+            chain: false,
+            patterns: Vec::new(),
+            samples: Vec::new(),
+            steps: Vec::new(),
+        }
+    }
+}
+
+impl Project {
+    /// Captures the state of a running session, `slices` are in frames at `sample_rate` and
+    /// `sample_bpms` are the tempos the project gave its samples
+    pub fn new(
+        bpm: u32,
+        swing: f32,
+        patterns: &[Pattern],
+        samples: &[PathBuf],
+        slices: &[SliceMap],
+        sample_bpms: &[Option<f32>],
+        sample_rate: u32,
+    ) -> Self {
+        let samples = samples
+            .iter()
+            .zip(slices)
+            .enumerate()
+            .map(|(idx, (path, slices))| ProjectSample {
+                path: path.clone(),
+                slices: slices
+                    .iter()
+                    .map(|slice| ProjectSlice {
+                        start: slice.start as f64 / sample_rate as f64,
+                        choke: slice.choke,
+                    })
+                    .collect(),
+                bpm: sample_bpms.get(idx).copied().flatten(),
+            })
+            .collect();
+        let steps = patterns
+            .iter()
+            .flat_map(|pattern| pattern.steps.iter())
+            .enumerate()
+            .filter_map(|(index, step)| match step {
+                Some(Step::On(step)) => Some(ProjectStep { index, step: *step }),
+                _ => None,
+            })
+            .collect();
+
+        Self {
+            version: PROJECT_VERSION,
+            bpm,
+            swing,
+            filter: filter::Settings::default(),
+            presets: Vec::new(),
+            preset: 0,
+            num_patterns: patterns.len(),
+            chain: false,
+            patterns: patterns
+                .iter()
+                .map(|pattern| ProjectPattern {
+                    length: pattern.length(),
+                    division: pattern.division,
+                })
+                .collect(),
+            samples,
+            steps,
+        }
+    }
+
+    /// Reads a project, sample paths come back resolved against the project's directory
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let mut project = toml::from_str::<Self>(&text)
+            .map_err(|e| e.to_string())?
+            .migrate()?;
+        project.validate()?;
+
+        let dir = path.parent().unwrap_or(Path::new(""));
+        for sample in project.samples.iter_mut() {
+            sample.path = dir.join(&sample.path);
+        }
+
+        Ok(project)
+    }
+
+    /// Writes the project, with sample paths relative to it where they share a directory
+    pub fn save(mut self, path: &Path) -> Result<(), String> {
+        let dir = path
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."))
+            .canonicalize()
+            .map_err(|e| e.to_string())?;
+        for sample in self.samples.iter_mut() {
+            if let Ok(absolute) = sample.path.canonicalize() {
+                sample.path = match absolute.strip_prefix(&dir) {
+                    Ok(relative) => relative.to_path_buf(),
+                    Err(_) => absolute,
+                };
+            }
+        }
+
+        let text = toml::to_string_pretty(&self).map_err(|e| e.to_string())?;
+        std::fs::write(path, text).map_err(|e| e.to_string())
+    }
+
+    /// Brings a project written by an older build up to the current schema
+    fn migrate(self) -> Result<Self, String> {
+        match self.version {
+            PROJECT_VERSION => Ok(self),
+            // Version 1 always chained its patterns one after another, which is now an option
+            1 => {
+                if self.num_patterns > 1 {
+                    println!("Version 1 project, its patterns play chained, set `chain = false` to play them at once");
+                }
+                Ok(Self {
+                    version: PROJECT_VERSION,
+                    chain: true,
+                    ..self
+                })
+            }
+            version if version > PROJECT_VERSION => Err(format!(
+                "Project is version {version}, this build only reads up to {PROJECT_VERSION}"
+            )),
+            version => Err(format!("Unknown project version {version}")),
+        }
+    }
+
+    /// Catches values that parse but can't be played
+    fn validate(&self) -> Result<(), String> {
+        for sample in &self.samples {
+            if let Some(bpm) = sample.bpm.filter(|&bpm| !tempo::is_valid(bpm)) {
+                return Err(format!(
+                    "{} is given a tempo of {bpm} BPM, expected {MIN_BPM} to {MAX_BPM}",
+                    sample.path.display()
+                ));
+            }
+        }
+
+        if self.presets.len() > PRESET_SLOTS {
+            return Err(format!(
+                "{} presets are saved, expected at most {PRESET_SLOTS}",
+                self.presets.len()
+            ));
+        }
+        for (slot, params) in self.presets.iter().enumerate() {
+            // Its reciprocal is the distortion's step size, so it can't be 0
+            if !(params.resolution > 0. && params.resolution.is_finite()) {
+                return Err(format!(
+                    "Preset {slot} has a resolution of {}, expected more than 0",
+                    params.resolution
+                ));
+            }
+            if !(0. ..=1.).contains(&params.mix) {
+                return Err(format!(
+                    "Preset {slot} has a mix of {}, expected 0 to 1",
+                    params.mix
+                ));
+            }
+        }
+        if self.preset >= PRESET_SLOTS {
+            return Err(format!(
+                "Starts on preset {}, expected 0 to {}",
+                self.preset,
+                PRESET_SLOTS - 1
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn patterns(&self) -> Vec<Pattern> {
+        let mut patterns: Vec<Pattern> = (0..self.num_patterns.max(1))
+            .map(|idx| {
+                let mut pattern = Pattern::default();
+                if let Some(saved) = self.patterns.get(idx) {
+                    pattern.set_length(saved.length);
+                    pattern.division = saved.division;
+                }
+                pattern
+            })
+            .collect();
+        for ProjectStep { index, step } in &self.steps {
+            if let Some(pattern) = patterns.get_mut(index / GRID_WIDTH) {
+                pattern.steps[index % GRID_WIDTH] = Some(Step::On(*step));
+            }
+        }
+        patterns
+    }
+
+    /// Every preset slot, the saved ones over the defaults
+    pub fn presets(&self) -> [destruction::Params; PRESET_SLOTS] {
+        let mut presets = destruction::Params::presets();
+        for (slot, saved) in presets.iter_mut().zip(&self.presets) {
+            *slot = *saved;
+        }
+        presets
+    }
+
+    /// The saved slice points for a sample of `len` frames at `sample_rate`, if there are any
+    pub fn slice_map(&self, sample: usize, len: usize, sample_rate: u32) -> Option<SliceMap> {
+        let slices = &self.samples.get(sample)?.slices;
+        if slices.is_empty() {
+            return None;
+        }
+
+        let starts = slices.iter().map(|slice| {
+            let start = (slice.start * sample_rate as f64).round() as usize;
+            (start, slice.choke)
+        });
+        Some(SliceMap::from_starts(len, starts))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_bpm(bpm: &str) -> Result<(), String> {
+        let text = format!("version = {PROJECT_VERSION}\n[[samples]]\npath = \"amen.wav\"\n{bpm}");
+        toml::from_str::<Project>(&text)
+            .map_err(|e| e.to_string())?
+            .validate()
+    }
+
+    #[test]
+    fn sample_tempos_must_be_playable() {
+        assert!(sample_bpm("").is_ok());
+        assert!(sample_bpm("bpm = 136.5").is_ok());
+        assert!(sample_bpm("bpm = 0").is_err());
+        assert!(sample_bpm("bpm = -120").is_err());
+        assert!(sample_bpm("bpm = 1000").is_err());
+        assert!(sample_bpm("bpm = nan").is_err());
+    }
+
+    fn presets(presets: &str) -> Result<(), String> {
+        let text = format!("version = {PROJECT_VERSION}\n{presets}");
+        toml::from_str::<Project>(&text)
+            .map_err(|e| e.to_string())?
+            .validate()
+    }
+
+    #[test]
+    fn presets_must_be_playable() {
+        assert!(presets("").is_ok());
+        assert!(presets("preset = 7\n[[presets]]\nresolution = 4\nmix = 0.5").is_ok());
+        assert!(presets("preset = 8").is_err());
+        assert!(presets("[[presets]]\nresolution = 0").is_err());
+        assert!(presets("[[presets]]\nresolution = -16").is_err());
+        assert!(presets("[[presets]]\nresolution = inf").is_err());
+        assert!(presets("[[presets]]\nmix = 1.5").is_err());
+        assert!(presets("[[presets]]\nmix = -0.1").is_err());
+        assert!(presets("[[presets]]\nmix = nan").is_err());
+        assert!(presets(&"[[presets]]\n".repeat(PRESET_SLOTS + 1)).is_err());
+    }
+}
@@ -0,0 +1,157 @@
+//! Offline rendering - drives the sampler without a sound card or grid attached
+
+use super::{
+    common::*,
+    event::{Event, EventKind, EventSender},
+    metro::{self, PulseTime, BEATS_PER_BAR, EARLY_PULSES, PPQN},
+    sampler::{Sampler, Step, StepBuilder},
+    sequence::{self, Chain, Pattern},
+};
+use std::path::Path;
+
+pub struct RenderSettings {
+    pub sample_rate: u32,
+    pub bpm: u32,
+    pub swing: f32,
+    pub bars: usize,
+    // Play the patterns one after another rather than all at once
+    pub chain: bool,
+}
+
+/// One pattern playing every slice in order, for when there's no sequence to render
+pub fn default_sequence() -> Vec<Pattern> {
+    let mut pattern = Pattern::default();
+    for (slice, step) in pattern.steps.iter_mut().enumerate() {
+        *step = Some(Step::On(StepBuilder::default().with_slice(slice)));
+    }
+    vec![pattern]
+}
+
+/// Renders `settings.bars` bars of `patterns` and returns the output frames
+pub fn render(
+    mut sampler: Sampler,
+    mut sender: EventSender,
+    patterns: &[Pattern],
+    settings: &RenderSettings,
+) -> Vec<Frame> {
+    sampler.set_sample_rate(settings.sample_rate as usize);
+
+    let pulse_frames = metro::frames_per_pulse(settings.bpm, settings.sample_rate);
+    let num_pulses = settings.bars as u64 * BEATS_PER_BAR * PPQN;
+    let mut output = Vec::with_capacity((num_pulses as f64 * pulse_frames).ceil() as usize);
+
+    sender
+        .push(Event::now(EventKind::Tempo(settings.bpm)))
+        .unwrap();
+
+    // Each pulse is scheduled ahead so steps can be dragged early. Pulse boundaries are kept in
+    // fractional frames so long renders don't drift.
+    let mut chain = settings.chain.then(Chain::default);
+    let mut schedule = |pulse: u64| {
+        let time = PulseTime {
+            pulse,
+            start: pulse as f64 * pulse_frames,
+            len: pulse_frames,
+        };
+        for (idx, step) in sequence::steps_at(patterns, chain.as_mut(), pulse) {
+            if let Some(event) = patterns[idx].event(step, time, settings.swing) {
+                // Drained every pulse, so there's always room
+                sender.push(event).unwrap()
+            }
+        }
+    };
+
+    (0..EARLY_PULSES.min(num_pulses)).for_each(&mut schedule);
+    for pulse in 0..num_pulses {
+        if pulse + EARLY_PULSES < num_pulses {
+            schedule(pulse + EARLY_PULSES);
+        }
+
+        let pulse_end = (pulse + 1) as f64 * pulse_frames;
+        while (output.len() as f64) < pulse_end.round() {
+            output.push(sampler.tick());
+        }
+    }
+
+    output
+}
+
+/// Writes stereo frames to a 32-bit float WAV
+pub fn write_wav(path: &Path, frames: &[Frame], sample_rate: u32) -> Result<(), String> {
+    let spec = hound::WavSpec {
+        channels: 2,
+        sample_rate,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+    };
+
+    let mut writer = hound::WavWriter::create(path, spec).map_err(|e| e.to_string())?;
+    for sample in frames.iter().flatten() {
+        writer.write_sample(*sample).map_err(|e| e.to_string())?;
+    }
+
+    writer.finalize().map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{sampler::tests::sampler, sequence::Division};
+
+    const SAMPLE_RATE: u32 = 48_000;
+    const BPM: u32 = 120;
+
+    fn settings(swing: f32, bars: usize) -> RenderSettings {
+        RenderSettings {
+            sample_rate: SAMPLE_RATE,
+            bpm: BPM,
+            swing,
+            bars,
+            chain: false,
+        }
+    }
+
+    #[test]
+    fn renders_whole_bars() {
+        let (sampler, sender) = sampler(&[0.5, 0.5], SAMPLE_RATE as usize);
+        let frames = render(sampler, sender, &default_sequence(), &settings(0., 3));
+        let bar = metro::frames_per_pulse(BPM, SAMPLE_RATE) * (BEATS_PER_BAR * PPQN) as f64;
+        assert_eq!(frames.len(), (3. * bar).round() as usize);
+    }
+
+    #[test]
+    fn swung_and_nudged_steps_trigger_on_their_own_frames() {
+        // A sixteenth nudged half a step late, then a swung thirty-second nudged half a step
+        // early. The second is sent after the first but lands before it.
+        let mut late = Pattern::default();
+        let step = StepBuilder::default().with_slice(0).with_nudge(0.5);
+        late.steps[0] = Some(Step::On(step));
+        let mut early = Pattern::default();
+        early.division = Division::ThirtySecond;
+        let step = StepBuilder::default().with_slice(1).with_nudge(-0.5);
+        early.steps[1] = Some(Step::On(step));
+
+        let levels = [0.25, 0.5];
+        let (sampler, sender) = sampler(&levels, SAMPLE_RATE as usize);
+        let swing = 0.25;
+        let frames = render(sampler, sender, &[late, early], &settings(swing, 1));
+
+        let pulse = metro::frames_per_pulse(BPM, SAMPLE_RATE);
+        let thirty_second = Division::ThirtySecond.pulses() as f64 * pulse;
+        let expected_early = (thirty_second * (1. + swing as f64 - 0.5)).round() as usize;
+        let expected_late = (Division::Sixteenth.pulses() as f64 * pulse * 0.5).round() as usize;
+        assert!(expected_early < expected_late);
+
+        let first = |level: f32| {
+            frames
+                .iter()
+                .position(|frame| frame[0] == level.tanh())
+                .unwrap()
+        };
+        assert!(frames[..expected_early]
+            .iter()
+            .all(|&frame| frame == [0., 0.]));
+        assert_eq!(first(levels[1]), expected_early);
+        assert_eq!(first(levels[0]), expected_late);
+    }
+}
@@ -0,0 +1,94 @@
+//! Offline sample rate conversion, run once when a sample is loaded
+
+use super::common::Frame;
+use std::{f64::consts::PI, str::FromStr};
+
+// Zero crossings either side of the sinc kernel's centre
+const SINC_ZEROS: usize = 32;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub enum Quality {
+    Linear,
+    #[default]
+    Sinc,
+}
+
+impl FromStr for Quality {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "linear" => Ok(Self::Linear),
+            "sinc" => Ok(Self::Sinc),
+            _ => Err(format!(
+                "Unknown resampler quality '{s}', expected linear or sinc"
+            )),
+        }
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0. {
+        1.
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+// Blackman window over -1..=1
+fn blackman(u: f64) -> f64 {
+    0.42 + 0.5 * (PI * u).cos() + 0.08 * (2. * PI * u).cos()
+}
+
+/// Converts `frames` from `from` Hz to `to` Hz
+pub fn resample(frames: Vec<Frame>, from: u32, to: u32, quality: Quality) -> Vec<Frame> {
+    if from == to || frames.is_empty() {
+        return frames;
+    }
+
+    let ratio = to as f64 / from as f64;
+    let out_len = (frames.len() as f64 * ratio).ceil() as usize;
+    match quality {
+        Quality::Linear => linear(&frames, ratio, out_len),
+        Quality::Sinc => windowed_sinc(&frames, ratio, out_len),
+    }
+}
+
+fn linear(frames: &[Frame], ratio: f64, out_len: usize) -> Vec<Frame> {
+    let last = frames.len() - 1;
+    (0..out_len)
+        .map(|n| {
+            let pos = n as f64 / ratio;
+            let idx = (pos.floor() as usize).min(last);
+            let frac = (pos - idx as f64) as f32;
+            let (fst, snd) = (frames[idx], frames[(idx + 1).min(last)]);
+            [0, 1].map(|ch| fst[ch] * (1. - frac) + snd[ch] * frac)
+        })
+        .collect()
+}
+
+fn windowed_sinc(frames: &[Frame], ratio: f64, out_len: usize) -> Vec<Frame> {
+    // When downsampling the kernel is widened so it also acts as the anti-aliasing filter
+    let cutoff = ratio.min(1.);
+    let half_width = SINC_ZEROS as f64 / cutoff;
+    let last = frames.len() as isize - 1;
+
+    (0..out_len)
+        .map(|n| {
+            let pos = n as f64 / ratio;
+            let lo = ((pos - half_width).ceil() as isize).max(0);
+            let hi = ((pos + half_width).floor() as isize).min(last);
+
+            let mut out = [0f64; 2];
+            for idx in lo..=hi {
+                let x = pos - idx as f64;
+                let weight = cutoff * sinc(cutoff * x) * blackman(x / half_width);
+                let frame = frames[idx as usize];
+                out[0] += frame[0] as f64 * weight;
+                out[1] += frame[1] as f64 * weight;
+            }
+
+            out.map(|sample| sample as f32)
+        })
+        .collect()
+}
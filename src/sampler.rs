@@ -1,22 +1,33 @@
-use std::{f32::consts::TAU, sync::mpsc::Receiver};
+use serde::{Deserialize, Serialize};
+use std::f32::consts::TAU;
 
-use super::destruction;
+use super::{
+    common::{Frame, DEFAULT_BPM},
+    destruction,
+    event::{Event, EventKind, EventReceiver, Param, EVENT_QUEUE_LEN},
+    filter::{self, Svf},
+    metro,
+    sequence::Division,
+    slices::{Slice, SliceMap},
+};
 
 const DEFAULT_SAMPLE_RATE: usize = 48_000;
-const DEFAULT_SLICES: usize = 16;
-
-const DISTORTION_PARAMS: destruction::Params = destruction::Params {
-    pregain: 4.,
-    postgain: 1.,
-    bit_depth: 32,
-    downsample_factor: 2,
-    resolution: 32.,
-    noise_amount: 0.1,
-    feedback: 0.1,
-};
+pub const DEFAULT_SLICES: usize = 16;
+pub const DEFAULT_VOICES: usize = 8;
+pub const MAX_SAMPLES: usize = 16;
+
+// Distortion parameters glide to new settings over about this long rather than zippering
+const FX_SMOOTHING_SECONDS: f32 = 0.02;
+
+// Grains for time-stretching, long enough to keep the pitch of low drums and short enough not
+// to smear hits. Two play at once, each starting halfway through the other.
+const GRAIN_SECONDS: f32 = 0.04;
+
+// Each retrigger repeat fades in and out over this long, or half the repeat if that's shorter
+const REPEAT_FADE_SECONDS: f32 = 0.002;
+// How long a step's filter envelope takes to fall most of the way back to its cutoff
+const DEFAULT_FILTER_DECAY: f32 = 0.2;
 
-// I'm going to need this for retrigger
-#[allow(unused)]
 fn hanning(phase: f32, size: usize) -> f32 {
     let x = (TAU * phase) / size as f32;
     0.5 * (1. - x.cos())
@@ -26,24 +37,74 @@ fn lerp(fst: f32, snd: f32, t: f32) -> f32 {
     fst * (1. - t) + snd * t
 }
 
-#[derive(Debug, Clone, Copy)]
+/// Balance-style pan, centre leaves both channels at unity so stereo sources aren't attenuated
+fn pan_gains(pan: f32) -> Frame {
+    let pan = pan.clamp(-1., 1.);
+    [(1. - pan).min(1.), (1. + pan).min(1.)]
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
 pub struct StepBuilder {
+    sample: usize,
     slice: usize,
     pitch: f32,
     direction: Direction,
+    pan: f32,
+    nudge: f32,
+    velocity: f32,
+    repeats: usize,
+    repeat_pitch: f32,
+    repeat_decay: f32,
+    stretch: Stretch,
+    // Overrides the sampler's cutoff for this step
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cutoff: Option<f32>,
+    filter_envelope: f32,
+    // Recalls a distortion preset slot as the step plays, kept until another step recalls one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fx: Option<usize>,
+    // Seconds the envelope takes to fall most of the way back, only set in the project file
+    filter_decay: f32,
+    // Set by the pattern the step plays from, repeats are spread across one of its steps and
+    // `Stretch::Fill` fills one
+    #[serde(skip)]
+    division: Division,
 }
 
 impl Default for StepBuilder {
     fn default() -> Self {
         Self {
+            sample: 0,
             slice: 0,
             pitch: 1.,
             direction: Direction::Forward,
+            pan: 0.,
+            nudge: 0.,
+            velocity: 1.,
+            repeats: 1,
+            repeat_pitch: 0.,
+            repeat_decay: 1.,
+            stretch: Stretch::default(),
+            cutoff: None,
+            filter_envelope: 0.,
+            fx: None,
+            filter_decay: DEFAULT_FILTER_DECAY,
+            division: Division::default(),
         }
     }
 }
 
 impl StepBuilder {
+    /// Index into the sampler's bank
+    pub fn sample(&self) -> usize {
+        self.sample
+    }
+
+    pub fn with_sample(self, sample: usize) -> Self {
+        Self { sample, ..self }
+    }
+
     pub fn slice(&self) -> usize {
         self.slice
     }
@@ -52,6 +113,7 @@ impl StepBuilder {
         Self { slice, ..self }
     }
 
+    /// Playback rate, 1 plays at the sample's own pitch
     pub fn pitch(&self) -> f32 {
         self.pitch
     }
@@ -67,39 +129,446 @@ impl StepBuilder {
     pub fn with_direction(self, direction: Direction) -> Self {
         Self { direction, ..self }
     }
+
+    /// -1 is hard left, 1 is hard right
+    pub fn pan(&self) -> f32 {
+        self.pan
+    }
+
+    pub fn with_pan(self, pan: f32) -> Self {
+        Self { pan, ..self }
+    }
+
+    /// How far the step is pushed off the grid in fractions of a step, negative is early
+    pub fn nudge(&self) -> f32 {
+        self.nudge
+    }
+
+    pub fn with_nudge(self, nudge: f32) -> Self {
+        Self { nudge, ..self }
+    }
+
+    /// How hard the step is hit, from 0 to 1. Scales the slice's level and sets the velocity
+    /// of MIDI notes
+    pub fn velocity(&self) -> f32 {
+        self.velocity
+    }
+
+    pub fn with_velocity(self, velocity: f32) -> Self {
+        Self { velocity, ..self }
+    }
+
+    /// How many times the slice is retriggered within the step, 1 plays it once
+    pub fn repeats(&self) -> usize {
+        self.repeats
+    }
+
+    pub fn with_repeats(self, repeats: usize) -> Self {
+        Self { repeats, ..self }
+    }
+
+    /// Semitones each repeat is pitched from the last
+    pub fn repeat_pitch(&self) -> f32 {
+        self.repeat_pitch
+    }
+
+    pub fn with_repeat_pitch(self, repeat_pitch: f32) -> Self {
+        Self {
+            repeat_pitch,
+            ..self
+        }
+    }
+
+    /// Level of each repeat relative to the last, 1 keeps them all the same
+    pub fn repeat_decay(&self) -> f32 {
+        self.repeat_decay
+    }
+
+    pub fn with_repeat_decay(self, repeat_decay: f32) -> Self {
+        Self {
+            repeat_decay,
+            ..self
+        }
+    }
+
+    pub fn stretch(&self) -> Stretch {
+        self.stretch
+    }
+
+    pub fn with_stretch(self, stretch: Stretch) -> Self {
+        Self { stretch, ..self }
+    }
+
+    /// Cutoff in Hz, `None` plays through the sampler's filter as it's set
+    pub fn cutoff(&self) -> Option<f32> {
+        self.cutoff
+    }
+
+    pub fn with_cutoff(self, cutoff: Option<f32>) -> Self {
+        Self { cutoff, ..self }
+    }
+
+    /// Octaves the cutoff jumps by as the step is hit, falling back over `filter_decay`
+    pub fn filter_envelope(&self) -> f32 {
+        self.filter_envelope
+    }
+
+    pub fn with_filter_envelope(self, filter_envelope: f32) -> Self {
+        Self {
+            filter_envelope,
+            ..self
+        }
+    }
+
+    /// The distortion preset slot the step switches to, if it switches
+    pub fn fx(&self) -> Option<usize> {
+        self.fx
+    }
+
+    pub fn with_fx(self, fx: Option<usize>) -> Self {
+        Self { fx, ..self }
+    }
+
+    pub fn with_division(self, division: Division) -> Self {
+        Self { division, ..self }
+    }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Default)]
 pub enum Step {
     On(StepBuilder),
+    #[default]
     Off,
 }
 
-impl Default for Step {
-    fn default() -> Self {
-        Self::Off
+impl Step {
+    pub fn nudge(&self) -> f32 {
+        match self {
+            Self::On(step) => step.nudge(),
+            Self::Off => 0.,
+        }
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum Direction {
     Forward,
     Backward,
 }
 
-pub struct Sampler {
-    samples: Vec<f32>,
-    slice_len: usize,
-    sample_rate: usize,
+/// How a step's length and pitch relate. `Keep`, `Fill` and `Tempo` play through grains, so
+/// `pitch` shifts the pitch without changing how long the slice lasts.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum Stretch {
+    /// Pitch changes the speed too, like a turntable
+    #[default]
+    Repitch,
+    /// Plays for the slice's own length whatever the pitch
+    Keep,
+    /// Stretched or squashed to last exactly one step
+    Fill,
+    /// Stretched by the sequencer's tempo over the sample's
+    Tempo,
+    /// Sped up or slowed down by the sequencer's tempo over the sample's, pitch and all
+    Varispeed,
+}
+
+impl Stretch {
+    /// In the order they're laid out on the grid
+    pub const ALL: [Self; 5] = [
+        Self::Repitch,
+        Self::Keep,
+        Self::Fill,
+        Self::Tempo,
+        Self::Varispeed,
+    ];
+}
+
+/// Two overlapping Hann-windowed grains reading at the voice's speed while its position moves
+/// on at `rate`, which sets the pitch and the length apart
+#[derive(Debug, Clone, Copy)]
+struct Grains {
+    rate: f32,
+    size: usize,
+    // Frames since the voice (or its last repeat) started
+    clock: usize,
+    // Where each grain started reading
+    starts: [f32; 2],
+}
+
+impl Grains {
+    fn new(rate: f32, size: usize, start: f32) -> Self {
+        Self {
+            rate,
+            size: size.max(2),
+            clock: 0,
+            starts: [start; 2],
+        }
+    }
+}
+
+/// Retriggers of a voice's slice still to come
+#[derive(Debug, Clone, Copy)]
+struct Repeats {
+    remaining: usize,
+    // Frames between repeats, and since the last one started
+    interval: f32,
+    elapsed: f32,
+    fade: f32,
+    // Applied to the speed and gains on each repeat
+    speed: f32,
+    decay: f32,
+    // The first hit keeps its transient, only repeats fade in
+    repeated: bool,
+    // The slice ran out before the next repeat was due
+    waiting: bool,
+}
+
+/// A voice's own filter, the sampler's settings with the step's cutoff and envelope
+#[derive(Debug, Clone, Copy)]
+struct VoiceFilter {
+    svfs: [Svf; 2],
+    mode: filter::Mode,
+    cutoff: f32,
+    resonance: f32,
+    // Octaves at the top of the envelope, and how much of it is left
+    envelope: f32,
+    level: f32,
+    // Multiplies `level` every frame
+    decay: f32,
+}
+
+impl VoiceFilter {
+    fn tick(&mut self, frame: Frame, sample_rate: f32) -> Frame {
+        let cutoff = self.cutoff * (self.envelope * self.level).exp2();
+        self.level *= self.decay;
+        let [left, right] = &mut self.svfs;
+        [
+            left.tick(frame[0], self.mode, cutoff, self.resonance, sample_rate),
+            right.tick(frame[1], self.mode, cutoff, self.resonance, sample_rate),
+        ]
+    }
+}
+
+/// A single playhead into the sample buffer
+#[derive(Debug, Clone, Copy)]
+struct Voice {
     playing: bool,
     pos: f32,
-    current_slice: usize,
-    speed: f32,
     start: f32,
-    end: f32,
+    sample: usize,
+    slice: Slice,
+    speed: f32,
     direction: Direction,
-    distortion: destruction::Destruction,
-    channel: Receiver<Step>,
+    gains: Frame,
+    // Trigger order, used to steal the oldest voice when the pool is full
+    started: u64,
+    repeats: Option<Repeats>,
+    // `pos` moves at the grains' rate rather than `speed` when stretching
+    grains: Option<Grains>,
+    filter: Option<VoiceFilter>,
+}
+
+impl Default for Voice {
+    fn default() -> Self {
+        Self {
+            playing: false,
+            pos: 0.,
+            start: 0.,
+            sample: 0,
+            slice: Slice {
+                start: 0,
+                end: 0,
+                choke: None,
+            },
+            speed: 1.,
+            direction: Direction::Forward,
+            gains: [1., 1.],
+            started: 0,
+            repeats: None,
+            grains: None,
+            filter: None,
+        }
+    }
+}
+
+/// Reads between frames at `pos`, towards the next frame in `direction`
+fn read(samples: &[Frame], pos: f32, direction: Direction) -> Frame {
+    let last = samples.len() - 1;
+    let fst = wrap(pos.floor() as usize, 0, last);
+    let snd = match direction {
+        Direction::Forward => wrap(fst + 1, 0, last),
+        Direction::Backward => {
+            if fst == 0 {
+                last
+            } else {
+                fst - 1
+            }
+        }
+    };
+    let frac = pos.fract();
+    let (fst, snd) = (samples[fst], samples[snd]);
+    [0, 1].map(|ch| lerp(fst[ch], snd[ch], frac))
+}
+
+impl Voice {
+    fn interpolate(&self, samples: &[Frame]) -> Frame {
+        let frame = read(samples, self.pos, self.direction);
+        [0, 1].map(|ch| frame[ch].tanh() * self.gains[ch])
+    }
+
+    /// The next frame from the grains, moving them on
+    fn granulate(&mut self, samples: &[Frame]) -> Frame {
+        let Some(grains) = &mut self.grains else {
+            return [0., 0.];
+        };
+        let (direction, speed) = (self.direction, self.speed);
+        let last = samples.len() as f32 - 1.;
+        let half = grains.size / 2;
+
+        let mut mix = [0., 0.];
+        for (idx, start) in grains.starts.iter_mut().enumerate() {
+            let age = (grains.clock + idx * half) % grains.size;
+            if age == 0 {
+                *start = self.pos;
+            }
+            // The first grain plays from the very start so the hit isn't faded in, and the
+            // second joins it halfway through
+            let level = match (grains.clock < half, idx) {
+                (true, 0) => 1.,
+                (true, _) => continue,
+                (false, _) => hanning(age as f32, grains.size),
+            };
+            let offset = age as f32 * speed;
+            let pos = match direction {
+                Direction::Forward => *start + offset,
+                Direction::Backward => *start - offset,
+            };
+            let frame = read(samples, pos.rem_euclid(last), direction);
+            mix[0] += frame[0] * level;
+            mix[1] += frame[1] * level;
+        }
+
+        grains.clock += 1;
+        let step = match direction {
+            Direction::Forward => grains.rate,
+            Direction::Backward => -grains.rate,
+        };
+        self.pos = wrap(self.pos + step, 0., last);
+        [0, 1].map(|ch| mix[ch].tanh() * self.gains[ch])
+    }
+
+    fn advance(&mut self, len: usize) {
+        match self.direction {
+            Direction::Forward => {
+                self.pos += self.speed;
+            }
+            Direction::Backward => {
+                self.pos -= self.speed;
+            }
+        }
+
+        self.pos = wrap(self.pos, 0., len as f32 - 1.);
+    }
+
+    fn slice_ended(&mut self) {
+        let Slice { start, end, .. } = self.slice;
+        let pos = self.pos as usize;
+        self.playing = match self.direction {
+            Direction::Forward => pos >= start && pos < end,
+            Direction::Backward => pos <= end && pos > start,
+        };
+
+        // Hold on to the voice until the next repeat
+        if let Some(repeats) = &mut self.repeats {
+            if !self.playing && repeats.remaining > 0 {
+                self.playing = true;
+                repeats.waiting = true;
+            }
+        }
+    }
+
+    /// Starts the next repeat when it's due, and returns the level of the fades around each
+    /// one. `None` while waiting on the next repeat with nothing to play.
+    fn retrigger(&mut self) -> Option<f32> {
+        let Some(repeats) = &mut self.repeats else {
+            return Some(1.);
+        };
+
+        repeats.elapsed += 1.;
+        if repeats.remaining > 0 && repeats.elapsed >= repeats.interval {
+            repeats.elapsed -= repeats.interval;
+            repeats.remaining -= 1;
+            repeats.repeated = true;
+            repeats.waiting = false;
+            self.pos = self.start;
+            if let Some(grains) = &mut self.grains {
+                grains.clock = 0;
+            }
+            if let Some(filter) = &mut self.filter {
+                filter.level = 1.;
+            }
+            self.speed *= repeats.speed;
+            self.gains = self.gains.map(|gain| gain * repeats.decay);
+        }
+        if repeats.waiting {
+            return None;
+        }
+
+        let size = (2. * repeats.fade) as usize;
+        let level = if repeats.repeated && repeats.elapsed < repeats.fade {
+            hanning(repeats.elapsed, size)
+        } else if repeats.remaining > 0 && repeats.interval - repeats.elapsed < repeats.fade {
+            hanning(repeats.interval - repeats.elapsed, size)
+        } else {
+            1.
+        };
+        Some(level)
+    }
+}
+
+/// A slot in the sample bank and the slices it's been cut into
+pub struct Sample {
+    frames: Vec<Frame>,
+    slices: SliceMap,
+    // The tempo it was played at, that `Stretch::Tempo` and `Stretch::Varispeed` lock to the
+    // sequencer's
+    bpm: f32,
+}
+
+impl Sample {
+    pub fn new(frames: Vec<Frame>, slices: SliceMap, bpm: f32) -> Self {
+        Self {
+            frames,
+            slices,
+            bpm,
+        }
+    }
+}
+
+pub struct Sampler {
+    bank: Vec<Sample>,
+    sample_rate: usize,
+    voices: Vec<Voice>,
+    voice_count: u64,
+    distortion: [destruction::Destruction; 2],
+    // The slot the distortion is gliding towards, and where it's got to
+    presets: [destruction::Params; destruction::PRESET_SLOTS],
+    preset: usize,
+    fx: destruction::Params,
+    filter: filter::Settings,
+    events: EventReceiver,
+    // Scheduled events taken off the ring and waiting for their frame, in the order they were
+    // sent. Allocated up front so the audio thread never has to, and `next_due` is the
+    // earliest frame among them.
+    pending: Vec<Event>,
+    next_due: u64,
+    // Played the moment they arrive rather than queueing behind steps scheduled ahead
+    live_events: Option<EventReceiver>,
+    // Frames rendered so far, the timeline events are scheduled against
+    frame: u64,
+    // For spreading retrigger repeats across a step
+    bpm: u32,
 }
 
 fn wrap<T>(n: T, lo: T, hi: T) -> T
@@ -116,119 +585,394 @@ where
 }
 
 impl Sampler {
-    pub fn new(samples: Vec<f32>, channel: Receiver<Step>) -> Self {
-        let len = samples.len();
-        let slice_len = len / DEFAULT_SLICES;
-        let end = len as f32;
+    /// Takes up to `MAX_SAMPLES` samples, any extra are dropped
+    pub fn new(mut bank: Vec<Sample>, events: EventReceiver) -> Self {
+        bank.truncate(MAX_SAMPLES);
         Self {
-            samples,
-            slice_len,
+            bank,
             sample_rate: DEFAULT_SAMPLE_RATE,
-            current_slice: 0,
-            pos: 0.,
-            playing: false,
-            speed: 1.,
-            start: 0.,
-            end,
-            distortion: destruction::Destruction::default(),
-            direction: Direction::Forward,
-            channel,
+            voices: vec![Voice::default(); DEFAULT_VOICES],
+            voice_count: 0,
+            distortion: Default::default(),
+            presets: destruction::Params::presets(),
+            preset: 0,
+            fx: destruction::Params::default(),
+            filter: filter::Settings::default(),
+            events,
+            pending: Vec::with_capacity(EVENT_QUEUE_LEN),
+            next_due: u64::MAX,
+            live_events: None,
+            frame: 0,
+            bpm: DEFAULT_BPM,
         }
     }
 
-    fn interpolate(&self) -> f32 {
-        let fst = wrap(
-            self.pos.floor() as usize,
-            self.start as usize,
-            self.end as usize - 1,
-        );
-        let snd = match self.direction {
-            Direction::Forward => wrap(fst + 1, self.start as usize, self.end as usize - 1),
-            Direction::Backward => wrap(fst - 1, self.start as usize, self.end as usize - 1),
-        };
-        let frac = self.pos.fract();
-        lerp(self.samples[fst], self.samples[snd], frac).tanh()
-    }
-
-    fn handle_message(&mut self) {
-        let mut count = 4;
-        while let Ok(step) = self.channel.try_recv() {
-            match step {
-                Step::On(StepBuilder {
-                    slice,
-                    pitch,
-                    direction,
-                }) => {
-                    self.current_slice = slice;
-                    self.pos = match direction {
-                        Direction::Forward => (slice * self.slice_len) as f32,
-                        Direction::Backward => (slice * self.slice_len + self.slice_len - 1) as f32,
-                    };
-                    self.speed = pitch;
-
-                    self.direction = direction;
-                    self.playing = true;
-                }
+    pub fn with_voices(self, num_voices: usize) -> Self {
+        Self {
+            voices: vec![Voice::default(); num_voices.max(1)],
+            ..self
+        }
+    }
 
-                Step::Off => self.playing = false,
-            }
+    /// Fills the distortion's preset slots and starts on `preset`
+    pub fn with_presets(
+        self,
+        presets: [destruction::Params; destruction::PRESET_SLOTS],
+        preset: usize,
+    ) -> Self {
+        let preset = preset.min(destruction::PRESET_SLOTS - 1);
+        Self {
+            presets,
+            preset,
+            fx: presets[preset],
+            ..self
+        }
+    }
 
-            count -= 1;
-            if count == 0 {
-                break;
+    /// Sets the filter every voice plays through
+    pub fn with_filter(self, filter: filter::Settings) -> Self {
+        Self { filter, ..self }
+    }
+
+    /// Takes events from something played by hand as well as the sequencer
+    pub fn with_live_events(self, live_events: EventReceiver) -> Self {
+        Self {
+            live_events: Some(live_events),
+            ..self
+        }
+    }
+
+    /// Sets the choke group of every slice with the same index in each sample, slices past the
+    /// end of `choke_groups` overlap freely
+    pub fn with_choke_groups(mut self, choke_groups: Vec<Option<usize>>) -> Self {
+        for sample in self.bank.iter_mut() {
+            for idx in 0..sample.slices.len() {
+                sample
+                    .slices
+                    .set_choke(idx, choke_groups.get(idx).copied().flatten());
             }
         }
+
+        self
     }
 
-    pub fn set_sample_rate(&mut self, sample_rate: usize) {
-        self.sample_rate = sample_rate;
-        self.distortion.set_sample_rate(sample_rate)
+    /// Where each sample in the bank is cut, for the grid to keep its own copy
+    pub fn slice_maps(&self) -> Vec<SliceMap> {
+        self.bank
+            .iter()
+            .map(|sample| sample.slices.clone())
+            .collect()
     }
 
-    fn advance(&mut self) {
-        match self.direction {
-            Direction::Forward => {
-                self.pos += self.speed;
-            }
-            Direction::Backward => {
-                self.pos -= self.speed;
+    /// Picks a free voice, or steals the oldest one if they're all busy
+    fn allocate_voice(&mut self) -> &mut Voice {
+        let idx = match self.voices.iter().position(|voice| !voice.playing) {
+            Some(free) => free,
+            None => self
+                .voices
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, voice)| voice.started)
+                .map(|(idx, _)| idx)
+                .unwrap_or(0),
+        };
+
+        &mut self.voices[idx]
+    }
+
+    fn trigger(&mut self, step: StepBuilder) {
+        let StepBuilder {
+            sample,
+            slice,
+            pitch,
+            direction,
+            pan,
+            velocity,
+            repeats,
+            repeat_pitch,
+            repeat_decay,
+            stretch,
+            cutoff,
+            filter_envelope,
+            filter_decay,
+            fx,
+            division,
+            ..
+        } = step;
+        if let Some(slot) = fx.filter(|&slot| slot < destruction::PRESET_SLOTS) {
+            self.preset = slot;
+        }
+        let slice = match self.bank.get(sample) {
+            Some(Sample { slices, .. }) if !slices.is_empty() => slices.get(slice % slices.len()),
+            // Nothing loaded in this slot
+            _ => None,
+        };
+        let Some(slice) = slice else {
+            return;
+        };
+        if slice.end <= slice.start {
+            return;
+        }
+
+        if let Some(group) = slice.choke {
+            self.voices
+                .iter_mut()
+                .filter(|voice| voice.playing && voice.slice.choke == Some(group))
+                .for_each(|voice| voice.playing = false);
+        }
+
+        let pulse = metro::frames_per_pulse(self.bpm, self.sample_rate as u32) as f32;
+        let step_frames = pulse * division.pulses() as f32;
+        let slice_frames = (slice.end - slice.start) as f32;
+        let tempo_ratio = self.bpm as f32 / self.bank[sample].bpm;
+        let rate = match stretch {
+            Stretch::Repitch | Stretch::Varispeed => None,
+            Stretch::Keep => Some(1.),
+            Stretch::Fill => Some(slice_frames / step_frames),
+            Stretch::Tempo => Some(tempo_ratio),
+        };
+        let speed = match stretch {
+            Stretch::Varispeed => pitch * tempo_ratio,
+            _ => pitch,
+        };
+
+        // Spread across the step at the current tempo
+        let repeats = (repeats > 1).then(|| {
+            let interval = step_frames / repeats as f32;
+            Repeats {
+                remaining: repeats - 1,
+                interval,
+                elapsed: 0.,
+                fade: (REPEAT_FADE_SECONDS * self.sample_rate as f32).min(interval / 2.),
+                speed: (repeat_pitch / 12.).exp2(),
+                decay: repeat_decay.clamp(0., 1.),
+                repeated: false,
+                waiting: false,
             }
+        });
+
+        let start = match direction {
+            Direction::Forward => slice.start as f32,
+            Direction::Backward => (slice.end - 1) as f32,
+        };
+        let grain_size = (GRAIN_SECONDS * self.sample_rate as f32) as usize;
+        let grains = rate.map(|rate| Grains::new(rate, grain_size, start));
+
+        // Left out entirely when there's nothing to filter, a step with its own cutoff or
+        // envelope lowpasses if the sampler's filter is off
+        let filtered =
+            self.filter.mode != filter::Mode::Off || cutoff.is_some() || filter_envelope != 0.;
+        let filter = filtered.then(|| VoiceFilter {
+            svfs: Default::default(),
+            mode: match self.filter.mode {
+                filter::Mode::Off => filter::Mode::Lowpass,
+                mode => mode,
+            },
+            cutoff: cutoff.unwrap_or(self.filter.cutoff),
+            resonance: self.filter.resonance,
+            envelope: filter_envelope,
+            level: 1.,
+            decay: (-1. / (filter_decay.max(0.001) * self.sample_rate as f32)).exp(),
+        });
+
+        self.voice_count += 1;
+        let started = self.voice_count;
+        let voice = self.allocate_voice();
+        *voice = Voice {
+            playing: true,
+            pos: start,
+            start,
+            sample,
+            slice,
+            speed,
+            direction,
+            gains: pan_gains(pan).map(|gain| gain * velocity.clamp(0., 1.)),
+            started,
+            repeats,
+            grains,
+            filter,
+        };
+    }
+
+    /// Frames rendered since the sampler was created
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    /// Acts on every live event and every scheduled one due by the current frame
+    fn handle_events(&mut self) {
+        while let Some(Ok(event)) = self.live_events.as_mut().map(|live| live.pop()) {
+            self.handle_event(event.kind);
         }
 
-        self.pos = wrap(self.pos, self.start, self.end - 1.);
+        // Anything left on the ring once `pending` is full waits for room
+        while self.pending.len() < self.pending.capacity() {
+            let Ok(event) = self.events.pop() else {
+                break;
+            };
+            self.next_due = self.next_due.min(event.frame);
+            self.pending.push(event);
+        }
+
+        if self.next_due > self.frame {
+            return;
+        }
+        let mut idx = 0;
+        self.next_due = u64::MAX;
+        while idx < self.pending.len() {
+            let frame = self.pending[idx].frame;
+            if frame <= self.frame {
+                let event = self.pending.remove(idx);
+                if let EventKind::Flush = event.kind {
+                    // Whatever's ahead of it was sent before it, anything after is kept
+                    self.pending.drain(..idx);
+                    idx = 0;
+                    self.next_due = u64::MAX;
+                }
+                self.handle_event(event.kind);
+            } else {
+                self.next_due = self.next_due.min(frame);
+                idx += 1;
+            }
+        }
     }
 
-    fn slice_ended(&mut self) {
-        match self.direction {
-            Direction::Forward => {
-                let slice_start = self.slice_len * self.current_slice;
-                let slice_end = slice_start + self.slice_len;
-                let pos = self.pos as usize;
-                self.playing = pos >= slice_start && pos < slice_end;
+    fn handle_event(&mut self, kind: EventKind) {
+        match kind {
+            EventKind::Trigger(step) => self.trigger(step),
+            EventKind::Stop | EventKind::Flush => self
+                .voices
+                .iter_mut()
+                .for_each(|voice| voice.playing = false),
+            EventKind::Param(Param::Slices { sample, count }) => {
+                if let Some(sample) = self.bank.get_mut(sample) {
+                    sample.slices.reslice(count)
+                }
             }
-            Direction::Backward => {
-                let slice_start = self.slice_len * self.current_slice;
-                let slice_end = slice_start + self.slice_len;
-                let pos = self.pos as usize;
-                self.playing = pos <= slice_end && pos > slice_start;
+            EventKind::Param(Param::Nudge {
+                sample,
+                slice,
+                frames,
+            }) => {
+                if let Some(sample) = self.bank.get_mut(sample) {
+                    sample.slices.nudge(slice, frames)
+                }
+            }
+            EventKind::Param(Param::Preset { slot, params }) => {
+                if let Some(preset) = self.presets.get_mut(slot) {
+                    *preset = params
+                }
             }
+            EventKind::Param(Param::Recall(slot)) => {
+                if slot < destruction::PRESET_SLOTS {
+                    self.preset = slot
+                }
+            }
+            EventKind::Tempo(bpm) => self.bpm = bpm,
         }
     }
 
-    fn process_effects(&mut self, sample: f32) -> f32 {
-        destruction::Destruction::tick(&mut self.distortion, sample, DISTORTION_PARAMS)
+    pub fn set_sample_rate(&mut self, sample_rate: usize) {
+        self.sample_rate = sample_rate;
+        self.distortion
+            .iter_mut()
+            .for_each(|distortion| distortion.set_sample_rate(sample_rate))
+    }
+
+    fn process_effects(&mut self, frame: Frame) -> Frame {
+        let [left, right] = &mut self.distortion;
+        [left.tick(frame[0], self.fx), right.tick(frame[1], self.fx)]
     }
 
-    pub fn tick(&mut self) -> f32 {
-        self.handle_message();
+    pub fn tick(&mut self) -> Frame {
+        self.handle_events();
+        self.frame += 1;
+
+        let smoothing = 1. - (-1. / (FX_SMOOTHING_SECONDS * self.sample_rate as f32)).exp();
+        self.fx = self.fx.smooth(self.presets[self.preset], smoothing);
+
+        let mut mix = [0., 0.];
+        let mut any_playing = false;
+        for voice in self.voices.iter_mut().filter(|voice| voice.playing) {
+            any_playing = true;
+            let Some(level) = voice.retrigger() else {
+                continue;
+            };
 
-        if self.playing {
-            self.advance();
-            let sample = self.interpolate();
-            self.slice_ended();
-            self.process_effects(sample)
+            let sample = &self.bank[voice.sample];
+            let frame = if voice.grains.is_some() {
+                voice.granulate(&sample.frames)
+            } else {
+                voice.advance(sample.frames.len());
+                voice.interpolate(&sample.frames)
+            };
+            let [left, right] = match &mut voice.filter {
+                Some(filter) => filter.tick(frame, self.sample_rate as f32),
+                None => frame,
+            };
+            mix[0] += left * level;
+            mix[1] += right * level;
+            voice.slice_ended();
+        }
+
+        if any_playing {
+            self.process_effects(mix)
         } else {
-            0.
+            [0., 0.]
         }
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::event::{self, Event, EventSender};
+
+    const SAMPLE_RATE: usize = 48_000;
+
+    /// A sampler with one sample cut into a second-long slice for each of `levels`, held at that
+    /// level, and the distortion dry so they come out as they went in
+    pub(crate) fn sampler(levels: &[f32], sample_rate: usize) -> (Sampler, EventSender) {
+        let frames = levels
+            .iter()
+            .flat_map(|&level| std::iter::repeat_n([level, level], sample_rate))
+            .collect::<Vec<_>>();
+        let slices = SliceMap::equal(frames.len(), levels.len());
+        let sample = Sample::new(frames, slices, 120.);
+        let dry = destruction::Params {
+            mix: 0.,
+            ..Default::default()
+        };
+        let (sender, receiver) = event::queue();
+        let mut sampler =
+            Sampler::new(vec![sample], receiver).with_presets([dry; destruction::PRESET_SLOTS], 0);
+        sampler.set_sample_rate(sample_rate);
+        (sampler, sender)
+    }
+
+    fn silent(frames: &[Frame]) -> bool {
+        frames.iter().all(|frame| frame[0] == 0.)
+    }
+
+    #[test]
+    fn reads_wrap_round_either_end() {
+        let samples = [[0., 0.], [1., 1.], [2., 2.], [3., 3.]];
+        assert_eq!(read(&samples, 1.5, Direction::Forward), [1.5, 1.5]);
+        assert_eq!(read(&samples, 3.5, Direction::Forward), [1.5, 1.5]);
+        assert_eq!(read(&samples, 2.5, Direction::Backward), [1.5, 1.5]);
+        assert_eq!(read(&samples, 0.5, Direction::Backward), [1.5, 1.5]);
+    }
+
+    #[test]
+    fn flush_drops_events_sent_before_it() {
+        let (mut sampler, mut sender) = sampler(&[0.5], SAMPLE_RATE);
+        let trigger = EventKind::Trigger(StepBuilder::default());
+        sender.push(Event::at(100, trigger)).unwrap();
+        sender.push(Event::now(EventKind::Flush)).unwrap();
+        sender.push(Event::at(200, trigger)).unwrap();
+
+        let frames = (0..300).map(|_| sampler.tick()).collect::<Vec<_>>();
+        assert!(silent(&frames[..200]));
+        assert!(!silent(&frames[200..]));
+    }
+}
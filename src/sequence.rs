@@ -0,0 +1,281 @@
+//! Patterns of steps. Every pattern plays at once, each looping over its own length at its own
+//! step division, so patterns of different lengths drift against each other polymetrically.
+//! Chained, they play one after another instead.
+
+use super::{
+    common::*,
+    event::{Event, EventKind},
+    metro::{PulseTime, StepTime, PPQN},
+    sampler::Step,
+};
+use serde::{Deserialize, Serialize};
+
+/// How long a pattern's steps are
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum Division {
+    Eighth,
+    EighthTriplet,
+    #[default]
+    Sixteenth,
+    SixteenthTriplet,
+    ThirtySecond,
+}
+
+impl Division {
+    /// Longest to shortest, the order they're laid out on the grid
+    pub const ALL: [Self; 5] = [
+        Self::Eighth,
+        Self::EighthTriplet,
+        Self::Sixteenth,
+        Self::SixteenthTriplet,
+        Self::ThirtySecond,
+    ];
+
+    pub fn steps_per_beat(self) -> u64 {
+        match self {
+            Self::Eighth => 2,
+            Self::EighthTriplet => 3,
+            Self::Sixteenth => 4,
+            Self::SixteenthTriplet => 6,
+            Self::ThirtySecond => 8,
+        }
+    }
+
+    /// Clock pulses in one step
+    pub fn pulses(self) -> u64 {
+        PPQN / self.steps_per_beat()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    pub steps: [Option<Step>; GRID_WIDTH],
+    // Steps played before looping, up to a row of the grid
+    length: usize,
+    pub division: Division,
+}
+
+impl Default for Pattern {
+    fn default() -> Self {
+        Self {
+            steps: [None; GRID_WIDTH],
+            length: GRID_WIDTH,
+            division: Division::default(),
+        }
+    }
+}
+
+impl Pattern {
+    pub fn length(&self) -> usize {
+        self.length
+    }
+
+    pub fn set_length(&mut self, length: usize) {
+        self.length = length.clamp(1, GRID_WIDTH);
+    }
+
+    /// The step that starts on `pulse`, if one does. Every pattern starts together on pulse 0.
+    pub fn step_at(&self, pulse: u64) -> Option<usize> {
+        let pulses = self.division.pulses();
+        pulse
+            .is_multiple_of(pulses)
+            .then(|| (pulse / pulses) as usize % self.length)
+    }
+
+    /// Pulses one pass through the pattern takes
+    pub fn span(&self) -> u64 {
+        self.length as u64 * self.division.pulses()
+    }
+
+    /// Where a step starting on the pulse at `time` lands, and how long it lasts
+    pub fn step_time(&self, time: PulseTime) -> StepTime {
+        StepTime {
+            start: time.start,
+            len: time.len * self.division.pulses() as f64,
+        }
+    }
+
+    /// The event for step `index` starting on the pulse at `time`, if the step is set
+    pub fn event(&self, index: usize, time: PulseTime, swing: f32) -> Option<Event> {
+        let step = self.steps[index]?;
+        let time = self.step_time(time);
+        let kind = match step {
+            Step::On(step) => EventKind::Trigger(step.with_division(self.division)),
+            Step::Off => EventKind::Stop,
+        };
+        Some(Event::at(time.frame(index, swing, step.nudge()), kind))
+    }
+}
+
+/// Plays patterns one after another, each once through its length, rather than all at once
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Chain {
+    // The pattern playing and the pulse it started on
+    pattern: usize,
+    start: u64,
+}
+
+impl Chain {
+    pub fn pattern(&self) -> usize {
+        self.pattern
+    }
+
+    /// The pattern and step that start on `pulse`, moving on to the next pattern once the one
+    /// playing has played through. A pulse before the pattern playing started seeks to it, and
+    /// one past its end starts the next pattern there.
+    pub fn step_at(&mut self, patterns: &[Pattern], pulse: u64) -> Option<(usize, usize)> {
+        if pulse < self.start || self.pattern >= patterns.len() {
+            self.seek(patterns, pulse);
+        }
+        let pattern = patterns.get(self.pattern)?;
+        let pulses = pattern.division.pulses();
+        let elapsed = pulse.checked_sub(self.start)?;
+
+        if elapsed >= pattern.span() {
+            self.pattern = (self.pattern + 1) % patterns.len();
+            self.start = pulse;
+            return Some((self.pattern, 0));
+        }
+        elapsed
+            .is_multiple_of(pulses)
+            .then(|| (self.pattern, (elapsed / pulses) as usize))
+    }
+
+    /// Moves to where the chain would be on `pulse` had it played through from pulse 0, for when
+    /// the transport restarts or jumps
+    pub fn seek(&mut self, patterns: &[Pattern], pulse: u64) {
+        *self = Self::default();
+        let total = patterns.iter().map(Pattern::span).sum::<u64>();
+        if total == 0 {
+            return;
+        }
+
+        let mut start = pulse - pulse % total;
+        for (idx, pattern) in patterns.iter().enumerate() {
+            if pulse < start + pattern.span() {
+                self.pattern = idx;
+                self.start = start;
+                return;
+            }
+            start += pattern.span();
+        }
+    }
+}
+
+/// Every pattern and step that starts on `pulse`, only from the pattern playing with a `chain`
+pub fn steps_at(
+    patterns: &[Pattern],
+    chain: Option<&mut Chain>,
+    pulse: u64,
+) -> Vec<(usize, usize)> {
+    match chain {
+        Some(chain) => chain.step_at(patterns, pulse).into_iter().collect(),
+        None => patterns
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, pattern)| Some((idx, pattern.step_at(pulse)?)))
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn steps_start_on_their_division() {
+        let mut pattern = Pattern::default();
+        assert_eq!(pattern.step_at(0), Some(0));
+        assert_eq!(pattern.step_at(5), None);
+        assert_eq!(pattern.step_at(6), Some(1));
+        assert_eq!(pattern.step_at(6 * 16), Some(0));
+
+        pattern.division = Division::EighthTriplet;
+        assert_eq!(pattern.step_at(8), Some(1));
+        assert_eq!(pattern.step_at(12), None);
+    }
+
+    #[test]
+    fn steps_loop_over_the_pattern_length() {
+        let mut pattern = Pattern::default();
+        pattern.set_length(3);
+        let steps = (0..PPQN * 2)
+            .filter_map(|pulse| pattern.step_at(pulse))
+            .collect::<Vec<_>>();
+        assert_eq!(steps, [0, 1, 2, 0, 1, 2, 0, 1]);
+
+        pattern.set_length(0);
+        assert_eq!(pattern.length(), 1);
+        pattern.set_length(100);
+        assert_eq!(pattern.length(), GRID_WIDTH);
+    }
+
+    #[test]
+    fn chain_plays_each_pattern_through_in_turn() {
+        let mut short = Pattern::default();
+        short.set_length(2);
+        short.division = Division::Eighth;
+        let patterns = [short, Pattern::default()];
+        let mut chain = Chain::default();
+
+        let steps = (0..PPQN * 4)
+            .filter_map(|pulse| chain.step_at(&patterns, pulse).map(|step| (pulse, step)))
+            .take(5)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            steps,
+            [
+                (0, (0, 0)),
+                (12, (0, 1)),
+                (24, (1, 0)),
+                (30, (1, 1)),
+                (36, (1, 2))
+            ]
+        );
+    }
+
+    #[test]
+    fn chain_follows_the_transport_backwards() {
+        let patterns = [Pattern::default(), Pattern::default()];
+        let span = patterns[0].span();
+        let mut chain = Chain::default();
+        for pulse in 0..span + 12 {
+            chain.step_at(&patterns, pulse);
+        }
+        assert_eq!(chain.pattern(), 1);
+
+        // Restarted from the top
+        assert_eq!(chain.step_at(&patterns, 0), Some((0, 0)));
+        assert_eq!(chain.step_at(&patterns, 6), Some((0, 1)));
+        // Jumped back into the first pattern from the second
+        assert_eq!(chain.step_at(&patterns, span + 6), Some((1, 0)));
+        assert_eq!(chain.step_at(&patterns, 12), Some((0, 2)));
+        assert_eq!(chain.pattern(), 0);
+    }
+
+    #[test]
+    fn chain_follows_the_transport_forwards() {
+        let patterns = [Pattern::default(), Pattern::default()];
+        let span = patterns[0].span();
+        let mut chain = Chain::default();
+        assert_eq!(chain.step_at(&patterns, 0), Some((0, 0)));
+
+        // Off the step grid within the pattern, it picks up on the next step
+        assert_eq!(chain.step_at(&patterns, 37), None);
+        assert_eq!(chain.step_at(&patterns, 42), Some((0, 7)));
+
+        // Past the end of the pattern, the next one starts there
+        assert_eq!(chain.step_at(&patterns, span + 41), Some((1, 0)));
+        assert_eq!(chain.step_at(&patterns, span + 47), Some((1, 1)));
+
+        chain.seek(&patterns, 3 * span + 12);
+        assert_eq!(chain.step_at(&patterns, 3 * span + 12), Some((1, 2)));
+    }
+
+    #[test]
+    fn layered_patterns_all_start_together() {
+        let patterns = [Pattern::default(), Pattern::default()];
+        assert_eq!(steps_at(&patterns, None, 0), [(0, 0), (1, 0)]);
+        assert_eq!(steps_at(&patterns, None, 1), []);
+    }
+}
@@ -0,0 +1,171 @@
+//! Where each sample in the bank is cut. The sampler and the grid both keep a copy, edited
+//! in lockstep through the same methods.
+
+use serde::{Deserialize, Serialize};
+
+pub const MAX_SLICES: usize = 64;
+
+/// A region of a sample, `end` is exclusive
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Slice {
+    pub start: usize,
+    pub end: usize,
+    // Slices sharing a choke group cut each other off, `None` lets tails overlap
+    pub choke: Option<usize>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SliceMap {
+    len: usize,
+    slices: Vec<Slice>,
+}
+
+impl SliceMap {
+    /// `count` equal slices over `len` frames
+    pub fn equal(len: usize, count: usize) -> Self {
+        let mut map = Self {
+            len,
+            slices: Vec::with_capacity(MAX_SLICES),
+        };
+        map.reslice(count);
+        map
+    }
+
+    /// A slice at each onset, the first slice always starts at the top of the buffer
+    pub fn from_onsets(len: usize, onsets: &[usize]) -> Self {
+        // Every slice in one group by default, the classic mono break chop
+        let starts = std::iter::once(0).chain(onsets.iter().copied());
+        Self::from_starts(len, starts.map(|start| (start, Some(0))))
+    }
+
+    /// A slice at each start point, with its choke group
+    pub fn from_starts(len: usize, starts: impl Iterator<Item = (usize, Option<usize>)>) -> Self {
+        let mut map = Self {
+            len,
+            slices: Vec::with_capacity(MAX_SLICES.max(starts.size_hint().0)),
+        };
+        map.cut(starts);
+        map
+    }
+
+    /// Replaces the slices with ones starting at each of `starts`, each running up to the start
+    /// of the next and the last to the end of the buffer. Doesn't allocate as long as there's
+    /// capacity, so it's safe on the audio thread.
+    fn cut(&mut self, starts: impl Iterator<Item = (usize, Option<usize>)>) {
+        let len = self.len;
+        self.slices.clear();
+        for (start, choke) in starts.filter(|&(start, _)| start < len) {
+            match self.slices.last_mut() {
+                Some(prev) if start <= prev.start => continue,
+                Some(prev) => prev.end = start,
+                None => (),
+            }
+            self.slices.push(Slice {
+                start,
+                end: len,
+                choke,
+            });
+        }
+    }
+
+    /// Re-cuts into `count` equal slices, keeping the first slice's choke group
+    pub fn reslice(&mut self, count: usize) {
+        let count = count.clamp(1, MAX_SLICES);
+        let slice_len = self.len / count;
+        let choke = self.slices.first().map_or(Some(0), |slice| slice.choke);
+        self.cut((0..count).map(|idx| (idx * slice_len, choke)));
+    }
+
+    /// Moves the start of `slice` by `frames`, dragging the end of the slice before it along
+    pub fn nudge(&mut self, slice: usize, frames: isize) {
+        let Some(current) = self.slices.get(slice).copied() else {
+            return;
+        };
+
+        // A slice can't move past the start of the one before it or its own end
+        let lo = match slice {
+            0 => 0,
+            _ => self.slices[slice - 1].start + 1,
+        };
+        let start = current
+            .start
+            .saturating_add_signed(frames)
+            .clamp(lo, current.end - 1);
+
+        self.slices[slice].start = start;
+        if slice > 0 {
+            self.slices[slice - 1].end = start;
+        }
+    }
+
+    pub fn set_choke(&mut self, slice: usize, choke: Option<usize>) {
+        if let Some(slice) = self.slices.get_mut(slice) {
+            slice.choke = choke;
+        }
+    }
+
+    pub fn get(&self, slice: usize) -> Option<Slice> {
+        self.slices.get(slice).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.slices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slices.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Slice> {
+        self.slices.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn starts(map: &SliceMap) -> Vec<usize> {
+        map.iter().map(|slice| slice.start).collect()
+    }
+
+    #[test]
+    fn equal_slices_cover_the_buffer() {
+        let map = SliceMap::equal(100, 4);
+        assert_eq!(starts(&map), [0, 25, 50, 75]);
+        assert_eq!(map.get(3).unwrap().end, 100);
+        assert_eq!(SliceMap::equal(100, 0).len(), 1);
+        assert_eq!(SliceMap::equal(1000, 1000).len(), MAX_SLICES);
+    }
+
+    #[test]
+    fn onsets_start_after_a_slice_at_zero() {
+        let map = SliceMap::from_onsets(100, &[10, 10, 5, 40, 200]);
+        assert_eq!(starts(&map), [0, 10, 40]);
+        assert_eq!(map.get(0).unwrap().end, 10);
+        assert!(map.iter().all(|slice| slice.choke == Some(0)));
+    }
+
+    #[test]
+    fn reslicing_keeps_the_choke_group() {
+        let mut map = SliceMap::from_starts(100, [(0, None), (50, None)].into_iter());
+        map.reslice(5);
+        assert_eq!(starts(&map), [0, 20, 40, 60, 80]);
+        assert!(map.iter().all(|slice| slice.choke.is_none()));
+    }
+
+    #[test]
+    fn nudges_drag_the_slice_before_and_stay_in_bounds() {
+        let mut map = SliceMap::equal(100, 4);
+        map.nudge(1, 5);
+        assert_eq!(map.get(1).unwrap().start, 30);
+        assert_eq!(map.get(0).unwrap().end, 30);
+
+        map.nudge(1, -100);
+        assert_eq!(map.get(1).unwrap().start, 1);
+        map.nudge(2, 100);
+        assert_eq!(map.get(2).unwrap().start, 74);
+        map.nudge(0, 10);
+        assert_eq!(map.get(0).unwrap().start, 0);
+    }
+}
@@ -1,17 +1,48 @@
-use super::sampler::Sampler;
+use super::{metro::Clock, sampler::Sampler};
 use cpal::traits::{DeviceTrait, HostTrait};
 
-pub fn setup(mut sample_player: Sampler) -> Result<cpal::Stream, String> {
-    let host = cpal::default_host();
+/// The output device and its config, found before any samples are loaded so they can be
+/// converted to the device's rate
+pub struct Output {
+    device: cpal::Device,
+    config: cpal::SupportedStreamConfig,
+}
+
+impl Output {
+    /// Finds the output device called `name`, or the system default
+    pub fn find(name: Option<&str>) -> Result<Self, String> {
+        let host = cpal::default_host();
+
+        let device = match name {
+            Some(name) => host
+                .output_devices()
+                .map_err(|e| e.to_string())?
+                .find(|device| device.name().is_ok_and(|n| n == name))
+                .ok_or_else(|| format!("Cannot find output device '{name}'"))?,
+            None => host
+                .default_output_device()
+                .ok_or("Cannot get default device")?,
+        };
+        let config = device.default_output_config().map_err(|e| e.to_string())?;
+        Ok(Self { device, config })
+    }
 
-    let device = host
-        .default_output_device()
-        .expect("Cannot get default device");
-    let config = device.default_output_config().map_err(|e| e.to_string())?;
+    pub fn sample_rate(&self) -> u32 {
+        self.config.sample_rate().0
+    }
+}
+
+/// Starts rendering `sample_player` to `output`, publishing the frame count on `clock`
+pub fn setup(
+    mut sample_player: Sampler,
+    output: Output,
+    clock: Clock,
+) -> Result<cpal::Stream, String> {
+    let Output { device, config } = output;
     sample_player.set_sample_rate(config.sample_rate().0 as usize);
 
     match config.sample_format() {
-        cpal::SampleFormat::F32 => make_stream(sample_player, device, &config.config()),
+        cpal::SampleFormat::F32 => make_stream(sample_player, device, &config.config(), clock),
         _ => Err("Cannot get f32 sample format".to_string()),
     }
 }
@@ -20,11 +51,17 @@ fn make_stream(
     sample_player: Sampler,
     device: cpal::Device,
     config: &cpal::StreamConfig,
+    clock: Clock,
 ) -> Result<cpal::Stream, String> {
     let on_error = |e| eprintln!("Error in audio thread: {e}");
 
     let stream = device
-        .build_output_stream(config, create_update_fn(sample_player), on_error, None)
+        .build_output_stream(
+            config,
+            create_update_fn(sample_player, config.channels as usize, clock),
+            on_error,
+            None,
+        )
         .expect("Cannot build stream");
 
     Ok(stream)
@@ -32,12 +69,24 @@ fn make_stream(
 
 fn create_update_fn(
     mut sample_player: Sampler,
+    channels: usize,
+    clock: Clock,
 ) -> impl FnMut(&mut [f32], &cpal::OutputCallbackInfo) {
     move |output: &mut [f32], _: &cpal::OutputCallbackInfo| {
-        for frame in output.chunks_mut(2) {
-            let sample = sample_player.tick();
+        // Anything scheduled before this buffer is already late
+        clock.set(sample_player.frame());
+        for frame in output.chunks_mut(channels) {
+            let [left, right] = sample_player.tick();
 
-            frame.fill(sample);
+            match frame {
+                [mono] => *mono = (left + right) * 0.5,
+                [l, r, rest @ ..] => {
+                    *l = left;
+                    *r = right;
+                    rest.fill(0.);
+                }
+                [] => (),
+            }
         }
     }
 }
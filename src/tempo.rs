@@ -0,0 +1,152 @@
+//! Tempo of loaded breaks, read from their name or tags or worked out from where their hits land,
+//! so they can be played back locked to the sequencer
+
+use super::metro::{BEATS_PER_BAR, MAX_BPM, MIN_BPM};
+use std::f32::consts::TAU;
+use std::path::Path;
+
+// Loops are assumed to be a whole number of beats long at a tempo in this range. It's just under
+// an octave so halving or doubling a tempo never gives a second guess.
+const LOOP_BPM_RANGE: std::ops::Range<f32> = 90.0..180.0;
+// Added to the score of beat counts that make whole bars, to break near ties
+const WHOLE_BAR_BONUS: f32 = 0.1;
+// Hits are lined up against a grid of this many steps to the beat
+const STEPS_PER_BEAT: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LoopTempo {
+    pub bpm: f32,
+    pub beats: usize,
+}
+
+impl LoopTempo {
+    /// A loop `len` frames long at `bpm`, rounded to the nearest beat
+    pub fn from_bpm(bpm: f32, len: usize, sample_rate: u32) -> Self {
+        let beats = seconds(len, sample_rate) * bpm / 60.;
+        Self {
+            bpm,
+            beats: (beats.round() as usize).max(1),
+        }
+    }
+
+    /// Bars the loop spans, counting a part bar as one
+    pub fn bars(&self) -> usize {
+        self.beats.div_ceil(BEATS_PER_BAR as usize).max(1)
+    }
+}
+
+/// Whether `bpm` is a tempo the sequencer can play at
+pub fn is_valid(bpm: f32) -> bool {
+    (MIN_BPM as f32..=MAX_BPM as f32).contains(&bpm)
+}
+
+fn seconds(len: usize, sample_rate: u32) -> f32 {
+    len as f32 / sample_rate as f32
+}
+
+/// A tempo written into a file name like `amen_136bpm.wav` or `think 98 BPM.flac`
+pub fn from_name(path: &Path) -> Option<f32> {
+    let stem = path.file_stem()?.to_str()?.to_lowercase();
+    let tokens = stem
+        .split(|c: char| !c.is_ascii_alphanumeric() && c != '.')
+        .filter(|token| !token.is_empty())
+        .collect::<Vec<_>>();
+
+    tokens.iter().enumerate().find_map(|(idx, token)| {
+        let number = match token.strip_suffix("bpm")? {
+            "" => *tokens.get(idx.checked_sub(1)?)?,
+            number => number,
+        };
+        number.parse::<f32>().ok().filter(|&bpm| is_valid(bpm))
+    })
+}
+
+/// Guesses the tempo of a loop `len` frames long that starts on a downbeat, picking the whole
+/// number of beats that best lines `onsets` up on a sixteenth grid. Without any onsets to go on
+/// it's assumed to be a power of two beats long.
+pub fn detect(len: usize, sample_rate: u32, onsets: &[usize]) -> LoopTempo {
+    let seconds = seconds(len, sample_rate);
+    let candidates = (1..)
+        .map(|beats| (beats, beats as f32 * 60. / seconds))
+        .skip_while(|(_, bpm)| *bpm < LOOP_BPM_RANGE.start)
+        .take_while(|(_, bpm)| *bpm < LOOP_BPM_RANGE.end);
+
+    let best = candidates
+        .map(|(beats, bpm)| {
+            let step = len as f32 / (beats * STEPS_PER_BEAT) as f32;
+            // 1 when every hit lands on a step, around 0 when they land anywhere
+            let alignment = onsets
+                .iter()
+                .map(|&onset| (TAU * onset as f32 / step).cos())
+                .sum::<f32>()
+                / onsets.len().max(1) as f32;
+            let whole_bars = beats % BEATS_PER_BAR as usize == 0;
+            let score = alignment + if whole_bars { WHOLE_BAR_BONUS } else { 0. };
+            (LoopTempo { bpm, beats }, score)
+        })
+        .max_by(|(_, a), (_, b)| a.total_cmp(b));
+
+    match best {
+        Some((tempo, _)) if !onsets.is_empty() => tempo,
+        _ => LoopTempo::from_bpm(power_of_two_bpm(seconds), len, sample_rate),
+    }
+}
+
+/// The tempo a loop `seconds` long would be at if it were a power of two beats long
+fn power_of_two_bpm(seconds: f32) -> f32 {
+    let mut bpm = 60. / seconds;
+    if !bpm.is_finite() {
+        return LOOP_BPM_RANGE.start;
+    }
+    while bpm < LOOP_BPM_RANGE.start {
+        bpm *= 2.;
+    }
+    while bpm >= LOOP_BPM_RANGE.end {
+        bpm /= 2.;
+    }
+    bpm
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tempo_is_read_from_file_names() {
+        assert_eq!(from_name(Path::new("amen_136bpm.wav")), Some(136.));
+        assert_eq!(from_name(Path::new("breaks/think 98 BPM.flac")), Some(98.));
+        assert_eq!(from_name(Path::new("loop-92.5bpm.wav")), Some(92.5));
+        assert_eq!(from_name(Path::new("amen.wav")), None);
+        assert_eq!(from_name(Path::new("bpm.wav")), None);
+        assert_eq!(from_name(Path::new("kick 1000bpm.wav")), None);
+        assert_eq!(from_name(Path::new("take 2 bpm 120.wav")), None);
+    }
+
+    #[test]
+    fn loops_without_onsets_are_a_power_of_two_beats() {
+        let tempo = detect(96_000, 48_000, &[]);
+        assert_eq!(
+            tempo,
+            LoopTempo {
+                bpm: 120.,
+                beats: 4
+            }
+        );
+        assert_eq!(tempo.bars(), 1);
+    }
+
+    #[test]
+    fn onsets_on_a_sixteenth_grid_pick_the_beat_count() {
+        // Six beats at 150 BPM, hits on the first, third and fourth sixteenths of each beat
+        let sample_rate = 48_000;
+        let sixteenth = sample_rate as usize * 60 / 150 / 4;
+        let onsets = (0..6 * 4)
+            .filter(|step| step % 4 != 1)
+            .map(|step| step * sixteenth)
+            .collect::<Vec<_>>();
+        let tempo = detect(6 * 4 * sixteenth, sample_rate, &onsets);
+        assert_eq!(tempo.beats, 6);
+        assert!((tempo.bpm - 150.).abs() < 0.01);
+        assert_eq!(tempo.bars(), 2);
+    }
+}
@@ -1,4 +1,10 @@
-use super::common::*;
+use super::{
+    common::*,
+    destruction::{Params, PRESET_SLOTS},
+    metro::{MAX_NUDGE, MAX_SWING},
+    sampler::Stretch,
+    sequence::Division,
+};
 
 pub trait Layout: Sized {
     type Context;
@@ -29,40 +35,273 @@ impl Page {
     }
 }
 
+// Pan keys on the direction row, hard left to hard right with centre on `PAN_CENTER`
+const PAN_START: usize = 9;
+const PAN_KEYS: usize = 7;
+const PAN_CENTER: usize = PAN_KEYS / 2;
+
+pub fn pan_to_key(pan: f32) -> usize {
+    (PAN_CENTER as f32 + pan * PAN_CENTER as f32)
+        .round()
+        .clamp(0., (PAN_KEYS - 1) as f32) as usize
+}
+
+pub fn key_to_pan(key: usize) -> f32 {
+    (key as f32 - PAN_CENTER as f32) / PAN_CENTER as f32
+}
+
+// Pitch fader, the centre key is unison and each key is a semitone, or a few cents of detune
+// while `Fine` is held
+const PITCH_ROW: usize = 3;
+const PITCH_CENTER: usize = GRID_WIDTH / 2;
+const CENTS_PER_FINE_KEY: f32 = 50. / PITCH_CENTER as f32;
+const FINE_KEY: usize = 7;
+// While held the pitch row moves the step off the grid instead, a key per sixteenth of a step
+const TIMING_KEY: usize = 8;
+const NUDGE_KEYS_PER_STEP: f32 = PITCH_CENTER as f32 / MAX_NUDGE;
+// And while this one is held, the step's velocity from a sixteenth on the left to full on the
+// right
+const VELOCITY_KEY: usize = 2;
+// Flip through slices 16 at a time when a sample has more than fit on a row
+const PREV_SLICES_KEY: usize = 5;
+const NEXT_SLICES_KEY: usize = 6;
+
+/// Splits a playback rate into whole semitones and cents of detune
+fn split_pitch(pitch: f32) -> (f32, f32) {
+    let cents = 1200. * pitch.log2();
+    let semitones = (cents / 100.).round();
+    (semitones, cents - semitones * 100.)
+}
+
+fn join_pitch(semitones: f32, cents: f32) -> f32 {
+    ((semitones * 100. + cents) / 1200.).exp2()
+}
+
+pub fn pitch_to_key(pitch: f32, fine: bool) -> usize {
+    let (semitones, cents) = split_pitch(pitch);
+    let offset = if fine {
+        cents / CENTS_PER_FINE_KEY
+    } else {
+        semitones
+    };
+    (PITCH_CENTER as f32 + offset)
+        .round()
+        .clamp(0., (GRID_WIDTH - 1) as f32) as usize
+}
+
+/// Moves either the semitones or the detune of `pitch` to `key`, keeping the other
+pub fn key_to_pitch(key: usize, pitch: f32, fine: bool) -> f32 {
+    let (semitones, cents) = split_pitch(pitch);
+    let offset = key as f32 - PITCH_CENTER as f32;
+    if fine {
+        join_pitch(semitones, offset * CENTS_PER_FINE_KEY)
+    } else {
+        join_pitch(offset, cents)
+    }
+}
+
+pub fn nudge_to_key(nudge: f32) -> usize {
+    (PITCH_CENTER as f32 + nudge * NUDGE_KEYS_PER_STEP)
+        .round()
+        .clamp(0., (GRID_WIDTH - 1) as f32) as usize
+}
+
+pub fn key_to_nudge(key: usize) -> f32 {
+    (key as f32 - PITCH_CENTER as f32) / NUDGE_KEYS_PER_STEP
+}
+
+// Below the parameter rows, how the step is stretched: repitched, kept at its own length,
+// filling the step or following the tempo. Like the retrigger rows it's one of the sequencer's
+// step rows, so the key holding the step open may be among its keys.
+const STRETCH_ROW: usize = 4;
+const STRETCH_KEYS: usize = Stretch::ALL.len();
+// Further along, holding this key turns the pitch row into the step's cutoff, lowest on the
+// left, with the last key leaving it to the sampler's filter. When it's the held step's own key
+// it can't be used, and letting go of it closes the editor as usual.
+const FILTER_KEY: usize = 6;
+const MIN_KEY_CUTOFF: f32 = 50.;
+const CUTOFF_OCTAVES_PER_KEY: f32 = 0.6;
+// And the rest of the row throws the cutoff down or up an octave or two as the step is hit
+const ENVELOPE_START: usize = 9;
+const ENVELOPE_KEYS: usize = 7;
+const ENVELOPE_CENTER: usize = ENVELOPE_KEYS / 2;
+const OCTAVES_PER_ENVELOPE_KEY: f32 = 2.;
+
+pub fn cutoff_to_key(cutoff: Option<f32>) -> usize {
+    cutoff.map_or(GRID_WIDTH - 1, |cutoff| {
+        ((cutoff / MIN_KEY_CUTOFF).log2() / CUTOFF_OCTAVES_PER_KEY)
+            .round()
+            .clamp(0., (GRID_WIDTH - 2) as f32) as usize
+    })
+}
+
+pub fn key_to_cutoff(key: usize) -> Option<f32> {
+    (key < GRID_WIDTH - 1).then(|| MIN_KEY_CUTOFF * (key as f32 * CUTOFF_OCTAVES_PER_KEY).exp2())
+}
+
+pub fn envelope_to_key(octaves: f32) -> usize {
+    (ENVELOPE_CENTER as f32 + octaves / OCTAVES_PER_ENVELOPE_KEY)
+        .round()
+        .clamp(0., (ENVELOPE_KEYS - 1) as f32) as usize
+}
+
+pub fn key_to_envelope(key: usize) -> f32 {
+    (key as f32 - ENVELOPE_CENTER as f32) * OCTAVES_PER_ENVELOPE_KEY
+}
+
+// Then the retrigger rows: repeats within the step, semitones each repeat is pitched from the
+// last, and how fast they fade away from none on the left. They're on the sequencer's step rows,
+// so one of their keys may be the step being held, which isn't drawn over them.
+const REPEATS_ROW: usize = 5;
+pub const REPEAT_COUNTS: [usize; 6] = [1, 2, 3, 4, 6, 8];
+const REPEAT_PITCH_ROW: usize = 6;
+const REPEAT_PITCH_KEYS: usize = 9;
+const REPEAT_PITCH_CENTER: usize = REPEAT_PITCH_KEYS / 2;
+const REPEAT_DECAY_ROW: usize = 7;
+const REPEAT_DECAY_KEYS: usize = 8;
+// The rest of the bottom row recalls one of the FX page's preset slots as the step plays. A
+// slot in the held step's column recalls like any other, only the held key closes the editor.
+const FX_SLOT_START: usize = REPEAT_DECAY_KEYS;
+
+pub fn repeats_to_key(repeats: usize) -> usize {
+    REPEAT_COUNTS
+        .iter()
+        .position(|&count| count >= repeats)
+        .unwrap_or(REPEAT_COUNTS.len() - 1)
+}
+
+pub fn repeat_pitch_to_key(semitones: f32) -> usize {
+    (REPEAT_PITCH_CENTER as f32 + semitones)
+        .round()
+        .clamp(0., (REPEAT_PITCH_KEYS - 1) as f32) as usize
+}
+
+pub fn key_to_repeat_pitch(key: usize) -> f32 {
+    key as f32 - REPEAT_PITCH_CENTER as f32
+}
+
+pub fn repeat_decay_to_key(decay: f32) -> usize {
+    ((1. - decay) * REPEAT_DECAY_KEYS as f32)
+        .round()
+        .clamp(0., (REPEAT_DECAY_KEYS - 1) as f32) as usize
+}
+
+pub fn key_to_repeat_decay(key: usize) -> f32 {
+    1. - key as f32 / REPEAT_DECAY_KEYS as f32
+}
+
+pub fn velocity_to_key(velocity: f32) -> usize {
+    (velocity * GRID_WIDTH as f32 - 1.)
+        .round()
+        .clamp(0., (GRID_WIDTH - 1) as f32) as usize
+}
+
+pub fn key_to_velocity(key: usize) -> f32 {
+    (key + 1) as f32 / GRID_WIDTH as f32
+}
+
 pub enum StepEditorWidget {
     SliceSelect(usize),
-    CurrentStep(usize),
+    SampleSelect(usize),
     Forward,
     Backward,
+    Pan(usize),
+    Pitch(usize),
+    Fine,
+    Timing,
+    Velocity,
+    // The pitch row while `Velocity` is held
+    VelocityFader(usize),
+    PrevSlices,
+    NextSlices,
+    Stretch(usize),
+    Filter,
+    // The pitch row while `Filter` is held
+    CutoffFader(usize),
+    FilterEnvelope(usize),
+    Repeats(usize),
+    RepeatPitch(usize),
+    RepeatDecay(usize),
+    // Lights the slot the step recalls, if it recalls one
+    FxSlot(Option<usize>),
 }
 
 impl Layout for StepEditorWidget {
-    type Context = ();
+    // How many keys of the slice or sample row have something behind them, for the rows that
+    // pick one
+    type Context = usize;
 
     fn hit(x: usize, y: usize) -> Option<Self> {
         use StepEditorWidget::*;
 
         if y == 0 && (0..GRID_WIDTH).contains(&x) {
             Some(SliceSelect(x))
+        } else if y == 2 && (0..GRID_WIDTH).contains(&x) {
+            Some(SampleSelect(x))
+        } else if y == PITCH_ROW && (0..GRID_WIDTH).contains(&x) {
+            Some(Pitch(x))
         } else if y == 1 {
             if (0..2).contains(&x) {
                 Some(Backward)
             } else if (3..5).contains(&x) {
                 Some(Forward)
+            } else if x == FINE_KEY {
+                Some(Fine)
+            } else if x == TIMING_KEY {
+                Some(Timing)
+            } else if x == VELOCITY_KEY {
+                Some(Velocity)
+            } else if x == PREV_SLICES_KEY {
+                Some(PrevSlices)
+            } else if x == NEXT_SLICES_KEY {
+                Some(NextSlices)
+            } else if (PAN_START..PAN_START + PAN_KEYS).contains(&x) {
+                Some(Pan(x - PAN_START))
+            } else {
+                None
+            }
+        } else if y == STRETCH_ROW {
+            if x < STRETCH_KEYS {
+                Some(Stretch(x))
+            } else if x == FILTER_KEY {
+                Some(Filter)
+            } else if (ENVELOPE_START..ENVELOPE_START + ENVELOPE_KEYS).contains(&x) {
+                Some(FilterEnvelope(x - ENVELOPE_START))
             } else {
                 None
             }
+        } else if y == REPEATS_ROW && x < REPEAT_COUNTS.len() {
+            Some(Repeats(x))
+        } else if y == REPEAT_PITCH_ROW && x < REPEAT_PITCH_KEYS {
+            Some(RepeatPitch(x))
+        } else if y == REPEAT_DECAY_ROW && x < REPEAT_DECAY_KEYS {
+            Some(RepeatDecay(x))
+        } else if y == REPEAT_DECAY_ROW
+            && (FX_SLOT_START..FX_SLOT_START + PRESET_SLOTS).contains(&x)
+        {
+            Some(FxSlot(Some(x - FX_SLOT_START)))
         } else {
             None
         }
     }
 
-    fn render(&self, page: &mut Page, on: bool, _: Self::Context) {
+    fn render(&self, page: &mut Page, on: bool, keys_used: Self::Context) {
         use StepEditorWidget::*;
+        let select = |idx: usize, index: usize| {
+            if idx == index {
+                ON
+            } else if idx < keys_used {
+                OFF
+            } else {
+                EMPTY
+            }
+        };
         match self {
-            SliceSelect(index) => (0..GRID_WIDTH)
-                .for_each(|idx| page.framebuffer[idx] = if idx == *index { ON } else { OFF }),
-            CurrentStep(index) => page.write_column(*index, on as u8 * ON),
+            SliceSelect(index) => {
+                (0..GRID_WIDTH).for_each(|idx| page.framebuffer[idx] = select(idx, *index))
+            }
+            SampleSelect(index) => (0..GRID_WIDTH)
+                .for_each(|idx| page.framebuffer[to_1d(idx, 2)] = select(idx, *index)),
             Forward => {
                 page.framebuffer[GRID_WIDTH..GRID_WIDTH + 2].fill(if on { OFF } else { ON });
                 page.framebuffer[GRID_WIDTH + 3..GRID_WIDTH + 5].fill(if on { ON } else { OFF });
@@ -71,13 +310,401 @@ impl Layout for StepEditorWidget {
                 page.framebuffer[GRID_WIDTH + 3..GRID_WIDTH + 5].fill(if on { OFF } else { ON });
                 page.framebuffer[GRID_WIDTH..GRID_WIDTH + 2].fill(if on { ON } else { OFF });
             }
+            Pan(key) => (0..PAN_KEYS).for_each(|idx| {
+                page.framebuffer[to_1d(PAN_START + idx, 1)] = if idx == *key {
+                    ON
+                } else if idx == PAN_CENTER {
+                    ACCENT
+                } else {
+                    OFF
+                }
+            }),
+            // Lit as a fader from unison out to the current value
+            Pitch(key) => (0..GRID_WIDTH).for_each(|idx| {
+                let (lo, hi) = (PITCH_CENTER.min(*key), PITCH_CENTER.max(*key));
+                page.framebuffer[to_1d(idx, PITCH_ROW)] = if (lo..=hi).contains(&idx) {
+                    ON
+                } else if idx == PITCH_CENTER {
+                    ACCENT
+                } else {
+                    OFF
+                }
+            }),
+            Fine => page.framebuffer[to_1d(FINE_KEY, 1)] = if on { ON } else { OFF },
+            Timing => page.framebuffer[to_1d(TIMING_KEY, 1)] = if on { ON } else { OFF },
+            Velocity => page.framebuffer[to_1d(VELOCITY_KEY, 1)] = if on { ON } else { OFF },
+            // Lit as a fader from the left edge up to the current value
+            VelocityFader(key) => (0..GRID_WIDTH).for_each(|idx| {
+                page.framebuffer[to_1d(idx, PITCH_ROW)] = if idx <= *key { ON } else { OFF }
+            }),
+            // `on` when there's another page of slices in that direction
+            PrevSlices => page.framebuffer[to_1d(PREV_SLICES_KEY, 1)] = on as u8 * ACCENT,
+            NextSlices => page.framebuffer[to_1d(NEXT_SLICES_KEY, 1)] = on as u8 * ACCENT,
+            Stretch(key) => (0..STRETCH_KEYS).for_each(|idx| {
+                page.framebuffer[to_1d(idx, STRETCH_ROW)] = if idx == *key { ON } else { OFF }
+            }),
+            Filter => page.framebuffer[to_1d(FILTER_KEY, STRETCH_ROW)] = if on { ON } else { OFF },
+            // Lit as a fader from the left edge, all the way across when the step has no
+            // cutoff of its own
+            CutoffFader(key) => (0..GRID_WIDTH).for_each(|idx| {
+                page.framebuffer[to_1d(idx, PITCH_ROW)] = if idx <= *key { ON } else { OFF }
+            }),
+            // Lit out from no envelope, like the pan keys
+            FilterEnvelope(key) => (0..ENVELOPE_KEYS).for_each(|idx| {
+                let (lo, hi) = (ENVELOPE_CENTER.min(*key), ENVELOPE_CENTER.max(*key));
+                page.framebuffer[to_1d(ENVELOPE_START + idx, STRETCH_ROW)] =
+                    if (lo..=hi).contains(&idx) {
+                        ON
+                    } else if idx == ENVELOPE_CENTER {
+                        ACCENT
+                    } else {
+                        OFF
+                    }
+            }),
+            Repeats(key) => (0..REPEAT_COUNTS.len()).for_each(|idx| {
+                page.framebuffer[to_1d(idx, REPEATS_ROW)] = if idx == *key { ON } else { OFF }
+            }),
+            // Lit as a fader out from no ramp, like the pitch row
+            RepeatPitch(key) => (0..REPEAT_PITCH_KEYS).for_each(|idx| {
+                let (lo, hi) = (REPEAT_PITCH_CENTER.min(*key), REPEAT_PITCH_CENTER.max(*key));
+                page.framebuffer[to_1d(idx, REPEAT_PITCH_ROW)] = if (lo..=hi).contains(&idx) {
+                    ON
+                } else if idx == REPEAT_PITCH_CENTER {
+                    ACCENT
+                } else {
+                    OFF
+                }
+            }),
+            RepeatDecay(key) => (0..REPEAT_DECAY_KEYS).for_each(|idx| {
+                page.framebuffer[to_1d(idx, REPEAT_DECAY_ROW)] = if idx <= *key { ON } else { OFF }
+            }),
+            FxSlot(slot) => (0..PRESET_SLOTS).for_each(|idx| {
+                page.framebuffer[to_1d(FX_SLOT_START + idx, REPEAT_DECAY_ROW)] =
+                    if *slot == Some(idx) { ON } else { OFF }
+            }),
+        }
+    }
+}
+
+/// Keys down the right edge that switch from the sequencer to the other pages and back
+pub enum NavWidget {
+    Slices,
+    Perform,
+    Fx,
+}
+
+impl Layout for NavWidget {
+    type Context = ();
+
+    fn hit(x: usize, y: usize) -> Option<Self> {
+        match (x, y) {
+            (15, 1) => Some(NavWidget::Slices),
+            (15, 2) => Some(NavWidget::Perform),
+            (15, 3) => Some(NavWidget::Fx),
+            _ => None,
+        }
+    }
+
+    fn render(&self, page: &mut Page, on: bool, _: Self::Context) {
+        match self {
+            NavWidget::Slices => page.framebuffer[to_1d(15, 1)] = if on { ON } else { OFF },
+            NavWidget::Perform => page.framebuffer[to_1d(15, 2)] = if on { ON } else { OFF },
+            NavWidget::Fx => page.framebuffer[to_1d(15, 3)] = if on { ON } else { OFF },
+        }
+    }
+}
+
+// Arms recording, and how what's played is written into the pattern on show
+const RECORD_KEY: (usize, usize) = (0, 1);
+const QUANTIZE_KEY: (usize, usize) = (2, 1);
+const OVERDUB_KEY: (usize, usize) = (3, 1);
+// Four rows of pads, a slice each, up to `MAX_SLICES`
+const PAD_ROW: usize = 4;
+
+/// The perform page, for playing slices from the grid and recording them as they're played
+pub enum PerformWidget {
+    SampleSelect(usize),
+    Record,
+    Quantize,
+    Overdub,
+    Pad(usize),
+}
+
+impl Layout for PerformWidget {
+    // Slices in the selected sample, pads past the end are left dark
+    type Context = usize;
+
+    fn hit(x: usize, y: usize) -> Option<Self> {
+        if y == 0 {
+            Some(PerformWidget::SampleSelect(x))
+        } else if (x, y) == RECORD_KEY {
+            Some(PerformWidget::Record)
+        } else if (x, y) == QUANTIZE_KEY {
+            Some(PerformWidget::Quantize)
+        } else if (x, y) == OVERDUB_KEY {
+            Some(PerformWidget::Overdub)
+        } else if y >= PAD_ROW {
+            Some(PerformWidget::Pad((y - PAD_ROW) * GRID_WIDTH + x))
+        } else {
+            None
+        }
+    }
+
+    fn render(&self, page: &mut Page, on: bool, slices: Self::Context) {
+        use PerformWidget::*;
+
+        let level = if on { ON } else { OFF };
+        match self {
+            SampleSelect(index) => (0..GRID_WIDTH)
+                .for_each(|idx| page.framebuffer[idx] = if idx == *index { ON } else { OFF }),
+            Record => page.framebuffer[to_1d(RECORD_KEY.0, RECORD_KEY.1)] = level,
+            Quantize => page.framebuffer[to_1d(QUANTIZE_KEY.0, QUANTIZE_KEY.1)] = level,
+            Overdub => page.framebuffer[to_1d(OVERDUB_KEY.0, OVERDUB_KEY.1)] = level,
+            // Lit while held, with every fourth slice picked out like the sequencer's beats
+            Pad(slice) => {
+                page.framebuffer[PAD_ROW * GRID_WIDTH + slice] = if *slice >= slices {
+                    EMPTY
+                } else if on {
+                    ON
+                } else if slice % 4 == 0 {
+                    ACCENT
+                } else {
+                    OFF
+                }
+            }
+        }
+    }
+}
+
+// Faders on the FX page stop short of the right edge and the nav keys
+const FX_KEYS: usize = GRID_WIDTH - 1;
+const PREGAIN_OCTAVES: f32 = 4.;
+const BIT_DEPTHS: [usize; FX_KEYS] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 14, 16, 32];
+const MAX_NOISE: f32 = 0.2;
+const MAX_FEEDBACK: f32 = 0.7;
+
+/// The distortion's parameters down the FX page, a row each below the preset slots
+#[derive(Debug, Clone, Copy)]
+pub enum FxParam {
+    Pregain,
+    BitDepth,
+    Downsample,
+    Resolution,
+    Noise,
+    Feedback,
+    Mix,
+}
+
+impl FxParam {
+    pub const ALL: [Self; 7] = [
+        Self::Pregain,
+        Self::BitDepth,
+        Self::Downsample,
+        Self::Resolution,
+        Self::Noise,
+        Self::Feedback,
+        Self::Mix,
+    ];
+
+    fn row(self) -> usize {
+        self as usize + 1
+    }
+
+    /// The key for this parameter's value in `params`
+    pub fn to_key(self, params: &Params) -> usize {
+        let last = (FX_KEYS - 1) as f32;
+        let key = match self {
+            Self::Pregain => params.pregain.max(1.).log2() / PREGAIN_OCTAVES * last,
+            Self::BitDepth => BIT_DEPTHS
+                .iter()
+                .position(|&depth| depth >= params.bit_depth)
+                .unwrap_or(FX_KEYS - 1) as f32,
+            Self::Downsample => params.downsample_factor.saturating_sub(1) as f32,
+            // A key per half octave of quantizing steps, from 2 up
+            Self::Resolution => (params.resolution.max(2.).log2() - 1.) * 2.,
+            Self::Noise => params.noise_amount / MAX_NOISE * last,
+            Self::Feedback => params.feedback / MAX_FEEDBACK * last,
+            Self::Mix => params.mix * last,
+        };
+        key.round().clamp(0., last) as usize
+    }
+
+    /// `params` with this parameter moved to `key`
+    pub fn with_key(self, key: usize, params: Params) -> Params {
+        let fraction = key as f32 / (FX_KEYS - 1) as f32;
+        match self {
+            Self::Pregain => Params {
+                pregain: (fraction * PREGAIN_OCTAVES).exp2(),
+                ..params
+            },
+            Self::BitDepth => Params {
+                bit_depth: BIT_DEPTHS[key.min(FX_KEYS - 1)],
+                ..params
+            },
+            Self::Downsample => Params {
+                downsample_factor: key + 1,
+                ..params
+            },
+            Self::Resolution => Params {
+                resolution: (1. + key as f32 / 2.).exp2(),
+                ..params
+            },
+            Self::Noise => Params {
+                noise_amount: fraction * MAX_NOISE,
+                ..params
+            },
+            Self::Feedback => Params {
+                feedback: fraction * MAX_FEEDBACK,
+                ..params
+            },
+            Self::Mix => Params {
+                mix: fraction,
+                ..params
+            },
+        }
+    }
+}
+
+/// The FX page, for shaping the distortion and storing it in preset slots steps can recall
+pub enum FxWidget {
+    Preset(usize),
+    Fader(FxParam, usize),
+}
+
+impl Layout for FxWidget {
+    type Context = ();
+
+    fn hit(x: usize, y: usize) -> Option<Self> {
+        if y == 0 && x < PRESET_SLOTS {
+            Some(FxWidget::Preset(x))
+        } else if (1..=FxParam::ALL.len()).contains(&y) && x < FX_KEYS {
+            Some(FxWidget::Fader(FxParam::ALL[y - 1], x))
+        } else {
+            None
+        }
+    }
+
+    fn render(&self, page: &mut Page, _: bool, _: Self::Context) {
+        match self {
+            FxWidget::Preset(slot) => (0..PRESET_SLOTS)
+                .for_each(|idx| page.framebuffer[idx] = if idx == *slot { ON } else { OFF }),
+            // Lit as a fader from the left edge up to the current value
+            FxWidget::Fader(param, key) => (0..FX_KEYS).for_each(|idx| {
+                page.framebuffer[to_1d(idx, param.row())] = if idx <= *key { ON } else { OFF }
+            }),
+        }
+    }
+}
+
+pub const SLICE_COUNTS: [usize; 5] = [4, 8, 16, 32, 64];
+pub const SLICE_PAGES: usize = 4;
+const COUNT_START: usize = 6;
+const NUDGE_SMALL: isize = 64;
+const NUDGE_LARGE: isize = 1024;
+
+/// The slice page, for re-cutting samples and moving slice start points
+pub enum SliceWidget {
+    Slice(usize),
+    SlicePage(usize),
+    Count(usize),
+    // Offset in frames
+    Nudge(isize),
+    SampleSelect(usize),
+}
+
+impl Layout for SliceWidget {
+    // How many keys of the slice row have a slice behind them
+    type Context = usize;
+
+    fn hit(x: usize, y: usize) -> Option<Self> {
+        use SliceWidget::*;
+
+        match (x, y) {
+            (x, 0) => Some(Slice(x)),
+            (x, 1) if x < SLICE_PAGES => Some(SlicePage(x)),
+            (x, 1) if (COUNT_START..COUNT_START + SLICE_COUNTS.len()).contains(&x) => {
+                Some(Count(x - COUNT_START))
+            }
+            (0, 2) => Some(Nudge(-NUDGE_LARGE)),
+            (1, 2) => Some(Nudge(-NUDGE_SMALL)),
+            (2, 2) => Some(Nudge(NUDGE_SMALL)),
+            (3, 2) => Some(Nudge(NUDGE_LARGE)),
+            (x, 4) => Some(SampleSelect(x)),
+            _ => None,
+        }
+    }
+
+    fn render(&self, page: &mut Page, on: bool, ctx: Self::Context) {
+        use SliceWidget::*;
+
+        match self {
+            Slice(key) => (0..GRID_WIDTH).for_each(|idx| {
+                page.framebuffer[idx] = if idx == *key && on {
+                    ON
+                } else if idx < ctx {
+                    OFF
+                } else {
+                    EMPTY
+                }
+            }),
+            SlicePage(index) => (0..SLICE_PAGES).for_each(|idx| {
+                page.framebuffer[to_1d(idx, 1)] = if idx == *index { ON } else { OFF }
+            }),
+            // Nothing is lit when the slices came from onset detection rather than a count
+            Count(index) => (0..SLICE_COUNTS.len()).for_each(|idx| {
+                page.framebuffer[to_1d(COUNT_START + idx, 1)] =
+                    if idx == *index && on { ON } else { OFF }
+            }),
+            Nudge(_) => {
+                page.framebuffer[to_1d(0, 2)..=to_1d(3, 2)].fill(if on { ON } else { OFF });
+            }
+            SampleSelect(index) => (0..GRID_WIDTH).for_each(|idx| {
+                page.framebuffer[to_1d(idx, 4)] = if idx == *index { ON } else { OFF }
+            }),
         }
     }
 }
 
+// Writes the project file, lit while held
+const SAVE_KEY: (usize, usize) = (0, 2);
+// Nudge the tempo a BPM at a time, or tap it in
+const TEMPO_DOWN_KEY: (usize, usize) = (12, 2);
+const TEMPO_UP_KEY: (usize, usize) = (13, 2);
+const TAP_KEY: (usize, usize) = (14, 2);
+// Arms recording the same as the perform page's record key, lit while armed
+const SEQUENCER_RECORD_KEY: (usize, usize) = (10, 2);
+// Swing amounts along the same row, from straight up to `MAX_SWING`
+const SWING_START: usize = 3;
+const SWING_KEYS: usize = 6;
+
+// Step divisions of the pattern on show, longest on the left
+const DIVISION_KEYS: usize = Division::ALL.len();
+// Hold and press a step to end the pattern there
+const LENGTH_KEY: (usize, usize) = (0, 3);
+
+pub fn swing_to_key(swing: f32) -> usize {
+    (swing / MAX_SWING * (SWING_KEYS - 1) as f32)
+        .round()
+        .clamp(0., (SWING_KEYS - 1) as f32) as usize
+}
+
+pub fn key_to_swing(key: usize) -> f32 {
+    key as f32 / (SWING_KEYS - 1) as f32 * MAX_SWING
+}
+
 pub enum SequencerWidget {
     Pattern(usize),
     PatternSelect(usize),
+    Save,
+    TempoDown,
+    TempoUp,
+    Tap,
+    Swing(usize),
+    Division(usize),
+    Length,
+    Record,
+    // A column past the end of the pattern on show
+    PastEnd(usize),
 }
 
 impl Layout for SequencerWidget {
@@ -85,6 +712,22 @@ impl Layout for SequencerWidget {
     fn hit(x: usize, y: usize) -> Option<Self> {
         if y == 0 {
             Some(SequencerWidget::PatternSelect(x))
+        } else if (x, y) == SAVE_KEY {
+            Some(SequencerWidget::Save)
+        } else if (x, y) == TEMPO_DOWN_KEY {
+            Some(SequencerWidget::TempoDown)
+        } else if (x, y) == TEMPO_UP_KEY {
+            Some(SequencerWidget::TempoUp)
+        } else if (x, y) == TAP_KEY {
+            Some(SequencerWidget::Tap)
+        } else if y == 2 && (SWING_START..SWING_START + SWING_KEYS).contains(&x) {
+            Some(SequencerWidget::Swing(x - SWING_START))
+        } else if y == 1 && x < DIVISION_KEYS {
+            Some(SequencerWidget::Division(x))
+        } else if (x, y) == LENGTH_KEY {
+            Some(SequencerWidget::Length)
+        } else if (x, y) == SEQUENCER_RECORD_KEY {
+            Some(SequencerWidget::Record)
         } else if (4 * GRID_WIDTH..128).contains(&to_1d(x, y)) {
             Some(SequencerWidget::Pattern(x))
         } else {
@@ -114,6 +757,29 @@ impl Layout for SequencerWidget {
                     }
                 }
             }
+            Save => page.framebuffer[to_1d(SAVE_KEY.0, SAVE_KEY.1)] = if on { ON } else { OFF },
+            TempoDown => {
+                page.framebuffer[to_1d(TEMPO_DOWN_KEY.0, TEMPO_DOWN_KEY.1)] =
+                    if on { ON } else { OFF }
+            }
+            TempoUp => {
+                page.framebuffer[to_1d(TEMPO_UP_KEY.0, TEMPO_UP_KEY.1)] = if on { ON } else { OFF }
+            }
+            Tap => page.framebuffer[to_1d(TAP_KEY.0, TAP_KEY.1)] = if on { ON } else { ACCENT },
+            Swing(key) => (0..SWING_KEYS).for_each(|idx| {
+                page.framebuffer[to_1d(SWING_START + idx, 2)] = if idx == *key { ON } else { OFF }
+            }),
+            Division(key) => (0..DIVISION_KEYS).for_each(|idx| {
+                page.framebuffer[to_1d(idx, 1)] = if idx == *key { ON } else { OFF }
+            }),
+            Length => {
+                page.framebuffer[to_1d(LENGTH_KEY.0, LENGTH_KEY.1)] = if on { ON } else { OFF }
+            }
+            Record => {
+                page.framebuffer[to_1d(SEQUENCER_RECORD_KEY.0, SEQUENCER_RECORD_KEY.1)] =
+                    if on { ON } else { OFF }
+            }
+            PastEnd(step) => page.write_column(*step, EMPTY),
         }
     }
 }